@@ -1,9 +1,15 @@
 use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
+use slog::warn;
+use std::sync::atomic::{AtomicBool, Ordering};
 use ulid::Ulid;
 
+use crate::lib::config::Config;
 use crate::lib::deploy::DeployMessage;
+use crate::lib::deploy_record::DeployRecordRead;
+use crate::lib::git_repo::GitRepoResolve;
 use crate::lib::pki::PkiCheck;
+use crate::lib::workspace::WorkspaceDiskCheck;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeployStruct {
@@ -14,6 +20,14 @@ pub struct DeployStruct {
     crypto_sign: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployGetQuery {
+    #[serde(default)]
+    plain_msg: String,  // the deploy id
+    #[serde(default)]
+    crypto_sign: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeployResult {
     id: String,
@@ -21,8 +35,10 @@ pub struct DeployResult {
 
 /// this handler uses json extractor
 pub async fn deploys_create(
+    config: web::Data<Config>,
     logger: web::Data<slog::Logger>,
     channel: web::Data<crossbeam_channel::Sender<String>>,
+    shutdown: web::Data<AtomicBool>,
     item: web::Json<DeployStruct>,
 ) -> HttpResponse {
 
@@ -30,27 +46,100 @@ pub async fn deploys_create(
         id: Ulid::new().to_string(),
     };
 
-    match PkiCheck::new(&result.id).call(&item.plain_msg, &item.crypto_sign, &logger.get_ref()) {
+    // draining deploys before exiting
+    if shutdown.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().json(result)
+    }
+
+    match PkiCheck::new(&result.id, &config.pki).call(&item.plain_msg, &item.crypto_sign, &logger.get_ref()) {
         Err(_) => {
             return HttpResponse::Unauthorized().json(result)
         },
         Ok(_) => {}
     }
 
-    if channel.is_full() {
-        return HttpResponse::TooManyRequests().json(result)
-    }
+    // only allowed repos are cloned
+    let (repo, path) = match GitRepoResolve::call(&config.git, &item.repo, &item.path) {
+        Err(e) => {
+            warn!(logger, "deploy_repo_forbidden: {}", e; "id" => &result.id);
+
+            return HttpResponse::Forbidden().json(result)
+        },
+        Ok(resolved) => {
+            resolved
+        }
+    };
+
+    match WorkspaceDiskCheck::new(&config).call() {
+        Ok(_) => {},
+        Err(e) => {
+            warn!(logger, "deploy_disk_exception: {}", e; "id" => &result.id);
+
+            return HttpResponse::InsufficientStorage().json(result)
+        }
+    };
 
     // create deploy message and send to thread using channel
 
     let deploy_message = DeployMessage {
         id: result.id.clone(),
-        repo: item.repo.clone(),
+        repo: repo,
         tag: item.tag.clone(),
-        path: item.path.clone(),
+        path: path,
     };
 
-    channel.send(serde_json::to_string(&deploy_message).unwrap()).unwrap();
+    // a shutdown may have started during the checks, the drain spools the channel once the
+    // server has stopped, so a message sent before then is kept
+    if shutdown.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().json(result)
+    }
+
+    // never block a worker on a full channel
+    match channel.try_send(serde_json::to_string(&deploy_message).unwrap()) {
+        Err(crossbeam_channel::TrySendError::Full(_)) => {
+            return HttpResponse::TooManyRequests().json(result)
+        },
+        Err(crossbeam_channel::TrySendError::Disconnected(_)) => {
+            return HttpResponse::ServiceUnavailable().json(result)
+        },
+        Ok(_) => {}
+    };
 
     HttpResponse::Accepted().json(result)
 }
+
+/// deploy record, e.g. state, git sha and image digest
+pub async fn deploys_get(
+    config: web::Data<Config>,
+    logger: web::Data<slog::Logger>,
+    path: web::Path<String>,
+    query: web::Query<DeployGetQuery>,
+) -> HttpResponse {
+    let id = path.into_inner();
+
+    // ids are ulids, don't let them escape the records dir
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return HttpResponse::NotFound().json(DeployResult { id: id })
+    }
+
+    // records have repo, commit and changelog details, the signed message is the deploy id
+    if config.pki.check && query.plain_msg != id {
+        return HttpResponse::Unauthorized().json(DeployResult { id: id })
+    }
+
+    match PkiCheck::new(&id, &config.pki).call(&query.plain_msg, &query.crypto_sign, &logger.get_ref()) {
+        Err(_) => {
+            return HttpResponse::Unauthorized().json(DeployResult { id: id })
+        },
+        Ok(_) => {}
+    }
+
+    match DeployRecordRead::call(&id) {
+        Err(_) => {
+            HttpResponse::NotFound().json(DeployResult { id: id })
+        },
+        Ok(record) => {
+            HttpResponse::Ok().json(record)
+        }
+    }
+}
@@ -0,0 +1,731 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use super::git_repo::{GitRepoNormalize, GitRepoUrl};
+
+const CONFIG_FILE_DEFAULT: &str = "config/deploybot.toml";
+// git verifies ssh signatures from 2.34 on
+const GIT_SSH_SIGNATURES_VERSION: (u32, u32) = (2, 34);
+
+//
+// application config, loaded from an optional toml file with env var overrides, e.g.
+//
+// listen_address = "0.0.0.0:80"
+//
+// [slack]
+// channel_name = "#gcp-deploys"
+//
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_address: String,
+    pub docker: DockerConfig,
+    pub git: GitConfig,
+    pub pki: PkiConfig,
+    pub shutdown: ShutdownConfig,
+    pub slack: SlackConfig,
+    pub workspace: WorkspaceConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DockerConfig {
+    pub builder: BuildBackend,  // default image builder, resources can override it
+    pub host_uri: String,  // docker daemon, required by the docker builder
+    pub buildx_builder: String,  // buildx builder that can export caches, required for cache_ref
+    pub kaniko_image: String,  // executor image, run as a pod per build
+    pub kaniko_kube_context: String,  // defaults to kubectl's current context
+    pub kaniko_namespace: String,
+    pub secrets: BTreeMap<String, String>,  // build secret name -> file, e.g. npm_token = "/etc/deploybot/npm_token"
+    pub registries: BTreeMap<String, RegistryConfig>,  // registry host -> credentials, e.g. "gcr.io"
+}
+
+// registry credentials, a username and password file, a token file, or a cloud token helper
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    pub username: String,
+    pub password_file: String,
+    pub token_file: String,
+    pub helper: Option<RegistryHelper>,
+    pub region: String,  // ecr region, defaults to the one in the registry host
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistryHelper {
+    Acr,
+    Ecr,
+    Gcr,
+}
+
+// git credentials for a host, overriding the default ssh key
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitHostConfig {
+    pub method: GitAuthMethod,
+    pub username: String,  // defaults to the url username, 'git' for ssh and 'x-access-token' for tokens
+    pub ssh_key: String,
+    pub token_file: String,  // https personal access token
+    pub app_id: String,  // github app
+    pub installation_id: String,
+    pub private_key_file: String,
+    pub api_url: String,
+}
+
+// an allowed repo, keyed by its normalized url, e.g. "github.com/org/api"
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitRepoConfig {
+    pub url: String,  // clone url, defaults to https for token credentials, ssh otherwise
+    pub resources_file: String,  // for deploy paths without a file, e.g. "kubernetes/resources.toml"
+    pub credentials: Option<GitHostConfig>,  // overrides the host credentials
+    pub refs: BTreeMap<String, GitRefPolicyConfig>,  // resource name -> refs it may be deployed from
+    pub require_signed: Vec<String>,  // resource names deployed only from signed tags or commits
+}
+
+// refs a resource may be deployed from, tag and branch names match glob patterns, e.g. "v*.*.*",
+// kept in the deploybot config as the repo's own files can't be trusted to restrict themselves,
+// policies are set on git.repos entries so they only apply to allowlisted repos
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitRefPolicyConfig {
+    pub tags: Vec<String>,
+    pub branches: Vec<String>,
+    pub commits: bool,  // full or abbreviated commit shas
+    pub protected_branches: Vec<String>,  // the commit must be reachable from one of these
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GitAuthMethod {
+    SshKey,
+    SshAgent,
+    Token,
+    GithubApp,
+}
+
+// image build backends, buildah and kaniko build without a docker daemon
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildBackend {
+    Docker,
+    Buildah,
+    Kaniko,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitConfig {
+    pub ssh_key: String,  // absolute, or relative to HOME
+    pub ssh_agent: bool,  // use ssh-agent instead of ssh_key
+    pub hosts: BTreeMap<String, GitHostConfig>,  // git host -> credentials, e.g. "github.com"
+    pub repos: BTreeMap<String, GitRepoConfig>,  // allowed repos, any repo if empty
+    pub mirror: bool,  // keep a bare mirror per repo and clone deploys from it
+    pub mirror_dir: String,
+    pub mirror_keep_days: u64,  // mirrors not fetched in this many days are removed, 0 keeps them
+    pub require_signed: bool,  // require signed tags for every resource
+    pub allowed_signers: String,  // ssh allowed signers file
+    pub gpg_home: String,  // gnupg home with the trusted public keys
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PkiConfig {
+    pub check: bool,
+    pub dir_any: String,
+}
+
+// Debug redacts the api token, the config is embedded in the Debug derived stages
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlackConfig {
+    pub api_token: String,
+    pub channel_name: String,
+    pub username: String,
+    pub color_error: String,
+    pub color_pending: String,
+    pub color_success: String,
+    pub spool_dir: String,
+}
+
+// on SIGTERM or SIGINT the running deploy gets this long to finish or reach a safe point
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    pub grace_secs: u64,
+}
+
+// deploy checkouts, removed or archived when a deploy completes, failed ones are kept for debugging
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkspaceConfig {
+    pub dir: String,  // checkouts are <dir>/<id>
+    pub keep_failed: usize,  // failed deploy checkouts kept, oldest removed first
+    pub archive_dir: String,  // archive completed checkouts as <id>.tar.gz instead of removing them
+    pub keep_archived: usize,
+    pub keep_records: usize,  // deploy records kept, the latest completed one per resource always is
+    pub min_free_mb: u64,  // refuse deploys below this much free disk in dir, the mirror dir or the deploybot tmp dir
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Missing(String, String),  // field, env var
+    Invalid(String, String),  // field, reason
+}
+
+#[derive(Debug)]
+pub struct ConfigLoad {}
+
+// a secret in Debug output, only whether it is set
+pub struct ConfigRedact<'a>(pub &'a str);
+
+impl<'a> fmt::Debug for ConfigRedact<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            write!(f, "\"\"")
+        } else {
+            write!(f, "\"<redacted>\"")
+        }
+    }
+}
+
+impl Default for DockerConfig {
+    fn default() -> DockerConfig {
+        DockerConfig {
+            builder: BuildBackend::Docker,
+            host_uri: "".to_string(),
+            buildx_builder: "".to_string(),
+            kaniko_image: "gcr.io/kaniko-project/executor:latest".to_string(),
+            kaniko_kube_context: "".to_string(),
+            kaniko_namespace: "default".to_string(),
+            secrets: BTreeMap::new(),
+            registries: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for GitConfig {
+    fn default() -> GitConfig {
+        GitConfig {
+            ssh_key: "".to_string(),
+            ssh_agent: false,
+            hosts: BTreeMap::new(),
+            repos: BTreeMap::new(),
+            mirror: true,
+            mirror_dir: "/var/tmp/deploybot/git".to_string(),
+            mirror_keep_days: 30,
+            require_signed: false,
+            allowed_signers: "".to_string(),
+            gpg_home: "".to_string(),
+        }
+    }
+}
+
+impl Default for GitHostConfig {
+    fn default() -> GitHostConfig {
+        GitHostConfig {
+            method: GitAuthMethod::SshKey,
+            username: "".to_string(),
+            ssh_key: "".to_string(),
+            token_file: "".to_string(),
+            app_id: "".to_string(),
+            installation_id: "".to_string(),
+            private_key_file: "".to_string(),
+            api_url: "https://api.github.com".to_string(),
+        }
+    }
+}
+
+impl BuildBackend {
+    pub fn parse(s: &str) -> Option<BuildBackend> {
+        match s.trim() {
+            "docker" => Some(BuildBackend::Docker),
+            "buildah" => Some(BuildBackend::Buildah),
+            "kaniko" => Some(BuildBackend::Kaniko),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BuildBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildBackend::Docker => write!(f, "docker"),
+            BuildBackend::Buildah => write!(f, "buildah"),
+            BuildBackend::Kaniko => write!(f, "kaniko"),
+        }
+    }
+}
+
+impl Default for PkiConfig {
+    fn default() -> PkiConfig {
+        PkiConfig {
+            check: true,
+            dir_any: "".to_string(),
+        }
+    }
+}
+
+impl fmt::Debug for SlackConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SlackConfig")
+            .field("api_token", &ConfigRedact(&self.api_token))
+            .field("channel_name", &self.channel_name)
+            .field("username", &self.username)
+            .field("color_error", &self.color_error)
+            .field("color_pending", &self.color_pending)
+            .field("color_success", &self.color_success)
+            .field("spool_dir", &self.spool_dir)
+            .finish()
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            grace_secs: 300,
+        }
+    }
+}
+
+impl Default for SlackConfig {
+    fn default() -> SlackConfig {
+        SlackConfig {
+            api_token: "".to_string(),
+            channel_name: "".to_string(),
+            username: "deploybot".to_string(),
+            color_error: "#cc0000".to_string(),
+            color_pending: "#6526f2".to_string(),
+            color_success: "#066f16".to_string(),
+            spool_dir: "/var/tmp/deploybot/slack".to_string(),
+        }
+    }
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> WorkspaceConfig {
+        WorkspaceConfig {
+            dir: "/var/tmp/deploybot".to_string(),
+            keep_failed: 3,
+            archive_dir: "".to_string(),
+            keep_archived: 20,
+            keep_records: 1000,
+            min_free_mb: 1024,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(file, e) => write!(f, "config file {} read error: {}", file, e),
+            ConfigError::Parse(file, e) => write!(f, "config file {} parse error: {}", file, e),
+            ConfigError::Missing(field, env) => write!(f, "config value {} is required, set it in the config file or with {}", field, env),
+            ConfigError::Invalid(field, reason) => write!(f, "config value {} is invalid: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigLoad {
+    //
+    // load config file from DEPLOYBOT_CONFIG (or the default path if it exists),
+    // apply env overrides and validate
+    //
+
+    pub fn call() -> Result<Config, ConfigError> {
+        let mut config = match dotenv::var("DEPLOYBOT_CONFIG") {
+            Ok(file) => {
+                ConfigLoad::_file_parse(&file)?
+            },
+            Err(_) if Path::new(CONFIG_FILE_DEFAULT).exists() => {
+                ConfigLoad::_file_parse(CONFIG_FILE_DEFAULT)?
+            },
+            Err(_) => {
+                Config::default()
+            }
+        };
+
+        ConfigLoad::_env_override(&mut config)?;
+        ConfigLoad::_validate(&mut config)?;
+
+        Ok(config)
+    }
+
+    fn _file_parse(file: &str) -> Result<Config, ConfigError> {
+        let toml_string = match std::fs::read_to_string(file) {
+            Err(e) => {
+                return Err(ConfigError::Read(file.to_string(), e))
+            },
+            Ok(value) => {
+                value
+            }
+        };
+
+        match toml::from_str(&toml_string) {
+            Err(e) => {
+                Err(ConfigError::Parse(file.to_string(), e))
+            },
+            Ok(config) => {
+                Ok(config)
+            }
+        }
+    }
+
+    fn _env_override(config: &mut Config) -> Result<(), ConfigError> {
+        ConfigLoad::_env_string("LISTEN_ADDRESS", &mut config.listen_address);
+
+        if let Ok(s) = dotenv::var("DOCKER_BUILDER") {
+            config.docker.builder = match BuildBackend::parse(&s) {
+                None => {
+                    return Err(ConfigError::Invalid("docker.builder".to_string(), format!("DOCKER_BUILDER must be docker, buildah or kaniko, got '{}'", s)))
+                },
+                Some(builder) => {
+                    builder
+                }
+            };
+        }
+
+        ConfigLoad::_env_string("DOCKER_HOST_URI", &mut config.docker.host_uri);
+        ConfigLoad::_env_string("DOCKER_BUILDX_BUILDER", &mut config.docker.buildx_builder);
+        ConfigLoad::_env_string("DOCKER_KANIKO_IMAGE", &mut config.docker.kaniko_image);
+        ConfigLoad::_env_string("DOCKER_KANIKO_KUBE_CONTEXT", &mut config.docker.kaniko_kube_context);
+        ConfigLoad::_env_string("DOCKER_KANIKO_NAMESPACE", &mut config.docker.kaniko_namespace);
+
+        ConfigLoad::_env_string("GIT_SSH_KEY", &mut config.git.ssh_key);
+        ConfigLoad::_env_bool("GIT_SSH_AGENT", "git.ssh_agent", &mut config.git.ssh_agent)?;
+        ConfigLoad::_env_bool("GIT_MIRROR", "git.mirror", &mut config.git.mirror)?;
+        ConfigLoad::_env_string("GIT_MIRROR_DIR", &mut config.git.mirror_dir);
+        ConfigLoad::_env_number("GIT_MIRROR_KEEP_DAYS", "git.mirror_keep_days", &mut config.git.mirror_keep_days)?;
+        ConfigLoad::_env_bool("GIT_REQUIRE_SIGNED", "git.require_signed", &mut config.git.require_signed)?;
+        ConfigLoad::_env_string("GIT_ALLOWED_SIGNERS", &mut config.git.allowed_signers);
+        ConfigLoad::_env_string("GIT_GPG_HOME", &mut config.git.gpg_home);
+
+        ConfigLoad::_env_bool("PKI_CHECK", "pki.check", &mut config.pki.check)?;
+        ConfigLoad::_env_string("PKI_DIR_ANY", &mut config.pki.dir_any);
+
+        ConfigLoad::_env_number("SHUTDOWN_GRACE_SECS", "shutdown.grace_secs", &mut config.shutdown.grace_secs)?;
+
+        ConfigLoad::_env_string("SLACK_API_TOKEN", &mut config.slack.api_token);
+        ConfigLoad::_env_string("SLACK_CHANNEL_NAME", &mut config.slack.channel_name);
+        ConfigLoad::_env_string("SLACK_USERNAME", &mut config.slack.username);
+        ConfigLoad::_env_string("SLACK_COLOR_ERROR", &mut config.slack.color_error);
+        ConfigLoad::_env_string("SLACK_COLOR_PENDING", &mut config.slack.color_pending);
+        ConfigLoad::_env_string("SLACK_COLOR_SUCCESS", &mut config.slack.color_success);
+        ConfigLoad::_env_string("SLACK_SPOOL_DIR", &mut config.slack.spool_dir);
+
+        ConfigLoad::_env_string("WORKSPACE_DIR", &mut config.workspace.dir);
+        ConfigLoad::_env_number("WORKSPACE_KEEP_FAILED", "workspace.keep_failed", &mut config.workspace.keep_failed)?;
+        ConfigLoad::_env_string("WORKSPACE_ARCHIVE_DIR", &mut config.workspace.archive_dir);
+        ConfigLoad::_env_number("WORKSPACE_KEEP_ARCHIVED", "workspace.keep_archived", &mut config.workspace.keep_archived)?;
+        ConfigLoad::_env_number("WORKSPACE_KEEP_RECORDS", "workspace.keep_records", &mut config.workspace.keep_records)?;
+        ConfigLoad::_env_number("WORKSPACE_MIN_FREE_MB", "workspace.min_free_mb", &mut config.workspace.min_free_mb)?;
+
+        Ok(())
+    }
+
+    fn _env_string(name: &str, value: &mut String) {
+        if let Ok(s) = dotenv::var(name) {
+            *value = s;
+        }
+    }
+
+    fn _env_bool(name: &str, field: &str, value: &mut bool) -> Result<(), ConfigError> {
+        match dotenv::var(name) {
+            Err(_) => {},
+            Ok(s) => {
+                *value = match s.trim() {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => {
+                        return Err(ConfigError::Invalid(field.to_string(), format!("{} must be 0 or 1, got '{}'", name, s)))
+                    }
+                };
+            }
+        };
+
+        Ok(())
+    }
+
+    fn _env_number<T: std::str::FromStr>(name: &str, field: &str, value: &mut T) -> Result<(), ConfigError> {
+        match dotenv::var(name) {
+            Err(_) => {},
+            Ok(s) => {
+                *value = match s.trim().parse::<T>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        return Err(ConfigError::Invalid(field.to_string(), format!("{} must be a number, got '{}'", name, s)))
+                    }
+                };
+            }
+        };
+
+        Ok(())
+    }
+
+    fn _validate(config: &mut Config) -> Result<(), ConfigError> {
+        ConfigLoad::_required("listen_address", "LISTEN_ADDRESS", &config.listen_address)?;
+
+        if config.listen_address.parse::<SocketAddr>().is_err() {
+            return Err(ConfigError::Invalid("listen_address".to_string(), format!("'{}' is not an ip:port address", config.listen_address)))
+        }
+
+        if config.docker.builder == BuildBackend::Docker {
+            ConfigLoad::_required("docker.host_uri", "DOCKER_HOST_URI", &config.docker.host_uri)?;
+        }
+
+        for (name, file) in config.docker.secrets.iter() {
+            if !Path::new(file).is_file() {
+                return Err(ConfigError::Invalid(format!("docker.secrets.{}", name), format!("file not found: {}", file)))
+            }
+        }
+
+        for (host, registry) in config.docker.registries.iter() {
+            ConfigLoad::_registry_validate(host, registry)?;
+        }
+
+        // the default ssh key is needed unless ssh-agent is used or every host is configured
+        if !config.git.ssh_agent && config.git.hosts.is_empty() {
+            ConfigLoad::_required("git.ssh_key", "GIT_SSH_KEY", &config.git.ssh_key)?;
+        }
+
+        if !config.git.ssh_key.is_empty() {
+            config.git.ssh_key = ConfigLoad::_home_path("git.ssh_key", &config.git.ssh_key)?;
+        }
+
+        // hosts are matched lowercased, like repo urls
+        for (host, mut git_host) in std::mem::take(&mut config.git.hosts) {
+            let field = format!("git.hosts.{}", host);
+
+            ConfigLoad::_git_host_validate(&field, &mut git_host)?;
+
+            if config.git.hosts.insert(host.to_lowercase(), git_host).is_some() {
+                return Err(ConfigError::Invalid(field, format!("duplicate of another {} entry", host.to_lowercase())))
+            }
+        }
+
+        // key repos by normalized url, so lookups match any url form of the repo
+        for (repo, mut git_repo) in std::mem::take(&mut config.git.repos) {
+            let field = format!("git.repos.{}", repo);
+
+            let key = match GitRepoNormalize::call(&repo) {
+                None => {
+                    return Err(ConfigError::Invalid(field, "not a repo url, e.g. github.com/org/api".to_string()))
+                },
+                Some(key) => {
+                    key
+                }
+            };
+
+            if !git_repo.url.is_empty() && GitRepoNormalize::call(&git_repo.url).as_ref() != Some(&key) {
+                return Err(ConfigError::Invalid(field, format!("url {} is not this repo", git_repo.url)))
+            }
+
+            if let Some(credentials) = git_repo.credentials.as_mut() {
+                ConfigLoad::_git_host_validate(&format!("{}.credentials", field), credentials)?;
+            }
+
+            // one clone url per repo, requests in any url form clone and mirror the same one
+            if git_repo.url.is_empty() {
+                let host = key.split('/').next().unwrap_or("");
+
+                let method = git_repo.credentials.as_ref().or_else(|| config.git.hosts.get(host)).map(|git_host| &git_host.method);
+
+                git_repo.url = GitRepoUrl::call(&key, matches!(method, Some(GitAuthMethod::Token) | Some(GitAuthMethod::GithubApp)));
+            }
+
+            for (name, refs) in git_repo.refs.iter() {
+                if refs.tags.is_empty() && refs.branches.is_empty() && !refs.commits {
+                    return Err(ConfigError::Invalid(format!("{}.refs.{}", field, name), "tags, branches or commits is required".to_string()))
+                }
+            }
+
+            if !git_repo.require_signed.is_empty() && config.git.allowed_signers.is_empty() && config.git.gpg_home.is_empty() {
+                return Err(ConfigError::Invalid(format!("{}.require_signed", field), "requires git.allowed_signers or git.gpg_home".to_string()))
+            }
+
+            if config.git.repos.insert(key.to_string(), git_repo).is_some() {
+                return Err(ConfigError::Invalid(field, format!("duplicate of another {} entry", key)))
+            }
+        }
+
+        if !config.git.allowed_signers.is_empty() && !Path::new(&config.git.allowed_signers).is_file() {
+            return Err(ConfigError::Invalid("git.allowed_signers".to_string(), format!("file not found: {}", config.git.allowed_signers)))
+        }
+
+        if !config.git.allowed_signers.is_empty() {
+            match ConfigLoad::_git_version() {
+                Some(version) if version >= GIT_SSH_SIGNATURES_VERSION => {},
+                Some((major, minor)) => {
+                    let (required_major, required_minor) = GIT_SSH_SIGNATURES_VERSION;
+
+                    return Err(ConfigError::Invalid("git.allowed_signers".to_string(), format!("ssh signatures require git {}.{} or newer, found {}.{}", required_major, required_minor, major, minor)))
+                },
+                None => {
+                    return Err(ConfigError::Invalid("git.allowed_signers".to_string(), "git is not installed".to_string()))
+                }
+            };
+        }
+
+        if !config.git.gpg_home.is_empty() && !Path::new(&config.git.gpg_home).is_dir() {
+            return Err(ConfigError::Invalid("git.gpg_home".to_string(), format!("'{}' is not a directory", config.git.gpg_home)))
+        }
+
+        // git verify-tag runs gpg, without it every signature check fails
+        if !config.git.gpg_home.is_empty() && std::process::Command::new("gpg").arg("--version").output().is_err() {
+            return Err(ConfigError::Invalid("git.gpg_home".to_string(), "gpg is not installed".to_string()))
+        }
+
+        if config.git.require_signed && config.git.allowed_signers.is_empty() && config.git.gpg_home.is_empty() {
+            return Err(ConfigError::Invalid("git.require_signed".to_string(), "requires allowed_signers or gpg_home".to_string()))
+        }
+
+        if config.git.mirror {
+            ConfigLoad::_required("git.mirror_dir", "GIT_MIRROR_DIR", &config.git.mirror_dir)?;
+        }
+
+        if config.pki.check {
+            ConfigLoad::_required("pki.dir_any", "PKI_DIR_ANY", &config.pki.dir_any)?;
+
+            if !Path::new(&config.pki.dir_any).is_dir() {
+                return Err(ConfigError::Invalid("pki.dir_any".to_string(), format!("'{}' is not a directory", config.pki.dir_any)))
+            }
+        }
+
+        ConfigLoad::_required("slack.api_token", "SLACK_API_TOKEN", &config.slack.api_token)?;
+        ConfigLoad::_required("slack.channel_name", "SLACK_CHANNEL_NAME", &config.slack.channel_name)?;
+        ConfigLoad::_required("slack.username", "SLACK_USERNAME", &config.slack.username)?;
+        ConfigLoad::_required("slack.spool_dir", "SLACK_SPOOL_DIR", &config.slack.spool_dir)?;
+
+        for (field, color) in [
+            ("slack.color_error", &config.slack.color_error),
+            ("slack.color_pending", &config.slack.color_pending),
+            ("slack.color_success", &config.slack.color_success),
+        ].iter() {
+            if !ConfigLoad::_color_valid(color) {
+                return Err(ConfigError::Invalid(field.to_string(), format!("'{}' is not a hex color, e.g. #cc0000", color)))
+            }
+        }
+
+        ConfigLoad::_required("workspace.dir", "WORKSPACE_DIR", &config.workspace.dir)?;
+
+        if !Path::new(&config.workspace.dir).is_absolute() {
+            return Err(ConfigError::Invalid("workspace.dir".to_string(), format!("'{}' is not an absolute path", config.workspace.dir)))
+        }
+
+        if !config.workspace.archive_dir.is_empty() && !Path::new(&config.workspace.archive_dir).is_absolute() {
+            return Err(ConfigError::Invalid("workspace.archive_dir".to_string(), format!("'{}' is not an absolute path", config.workspace.archive_dir)))
+        }
+
+        Ok(())
+    }
+
+    // resolve a path relative to HOME
+    fn _home_path(field: &str, path: &str) -> Result<String, ConfigError> {
+        if Path::new(path).is_absolute() {
+            return Ok(path.to_string())
+        }
+
+        match dotenv::var("HOME") {
+            Err(_) => {
+                Err(ConfigError::Invalid(field.to_string(), "relative path requires HOME to be set".to_string()))
+            },
+            Ok(home) => {
+                Ok(format!("{}/{}", home, path))
+            }
+        }
+    }
+
+    fn _git_host_validate(field: &str, git_host: &mut GitHostConfig) -> Result<(), ConfigError> {
+        let field = field.to_string();
+
+        let files = match git_host.method {
+            GitAuthMethod::SshKey => {
+                if git_host.ssh_key.is_empty() {
+                    return Err(ConfigError::Invalid(field, "ssh_key is required".to_string()))
+                }
+
+                git_host.ssh_key = ConfigLoad::_home_path(&field, &git_host.ssh_key)?;
+
+                vec![&git_host.ssh_key]
+            },
+            GitAuthMethod::SshAgent => {
+                vec![]
+            },
+            GitAuthMethod::Token => {
+                if git_host.token_file.is_empty() {
+                    return Err(ConfigError::Invalid(field, "token_file is required".to_string()))
+                }
+
+                vec![&git_host.token_file]
+            },
+            GitAuthMethod::GithubApp => {
+                if git_host.app_id.is_empty() || git_host.installation_id.is_empty() || git_host.private_key_file.is_empty() {
+                    return Err(ConfigError::Invalid(field, "app_id, installation_id and private_key_file are required".to_string()))
+                }
+
+                vec![&git_host.private_key_file]
+            }
+        };
+
+        for file in files.iter() {
+            if !Path::new(file).is_file() {
+                return Err(ConfigError::Invalid(field, format!("file not found: {}", file)))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn _registry_validate(host: &str, registry: &RegistryConfig) -> Result<(), ConfigError> {
+        let field = format!("docker.registries.{}", host);
+
+        let sources = [!registry.password_file.is_empty(), !registry.token_file.is_empty(), registry.helper.is_some()];
+
+        if sources.iter().filter(|set| **set).count() != 1 {
+            return Err(ConfigError::Invalid(field, "set one of password_file, token_file or helper".to_string()))
+        }
+
+        if !registry.password_file.is_empty() && registry.username.is_empty() {
+            return Err(ConfigError::Invalid(field, "password_file requires username".to_string()))
+        }
+
+        for file in [&registry.password_file, &registry.token_file].iter() {
+            if !file.is_empty() && !Path::new(file).is_file() {
+                return Err(ConfigError::Invalid(field, format!("file not found: {}", file)))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn _required(field: &str, env: &str, value: &str) -> Result<(), ConfigError> {
+        if value.trim().is_empty() {
+            return Err(ConfigError::Missing(field.to_string(), env.to_string()))
+        }
+
+        Ok(())
+    }
+
+    // e.g. "git version 2.39.2" -> (2, 39)
+    fn _git_version() -> Option<(u32, u32)> {
+        let output = std::process::Command::new("git").arg("--version").output().ok()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        let mut parts = stdout.split_whitespace().nth(2)?.split('.');
+
+        Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+    }
+
+    fn _color_valid(color: &str) -> bool {
+        color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+    }
+}
@@ -1,9 +1,20 @@
 use serde::{Deserialize, Serialize};
 use slog::*;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time;
 
-use super::runner::StageRunner;
+use super::config::Config;
+use super::git::GitMirrorPrune;
+use super::runner::{StageRunner, StageRunnerHandles};
+use super::workspace::WorkspaceRetain;
 
-use crate::lib::fs::{FsRemove, FsTouch};
+use crate::lib::fs::{FsQueue, FsRemove, FsTouch};
+
+// how often an idle deploy thread checks for a shutdown
+const DEPLOY_RECV_TIMEOUT_MILLIS: u64 = 500;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeployMessage {
@@ -13,19 +24,64 @@ pub struct DeployMessage {
     pub path: String,
 }
 
+#[derive(Debug)]
+pub struct DeploySpool {
+    pub dir: String,
+}
+
 #[derive(Debug)]
 pub struct DeployThread {
     pub deploy_channel: crossbeam_channel::Receiver<String>,  // receiver channel
     pub slack_channel: crossbeam_channel::Sender<String>,  // slack channel
+    pub config: Config,
+    pub shutdown: Arc<AtomicBool>,
     pub logger: slog::Logger,
 }
 
+impl DeploySpool {
+    pub fn new() -> DeploySpool {
+        DeploySpool {
+            dir: FsQueue::dir(),
+        }
+    }
+
+    // file names are the deploy ids, ulids, so they sort in request order
+    pub fn write(&self, id: &str, data: &str) -> std::io::Result<String> {
+        fs::create_dir_all(&self.dir)?;
+
+        let path = format!("{}/{}.json", self.dir, id);
+
+        fs::write(&path, data)?;
+
+        Ok(path)
+    }
+
+    // list spooled deploy files, oldest first
+    pub fn list(&self) -> std::io::Result<Vec<String>> {
+        if !Path::new(&self.dir).exists() {
+            return Ok(Vec::new())
+        }
+
+        let mut files: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().to_str().unwrap().to_string())
+            .filter(|path| path.ends_with(".json"))
+            .collect();
+
+        files.sort();
+
+        Ok(files)
+    }
+}
+
 impl DeployThread {
 
-    pub fn new(deploy_channel: crossbeam_channel::Receiver<String>, slack_channel: crossbeam_channel::Sender<String>, logger: slog::Logger) -> DeployThread {
+    pub fn new(deploy_channel: crossbeam_channel::Receiver<String>, slack_channel: crossbeam_channel::Sender<String>, config: Config, shutdown: Arc<AtomicBool>, logger: slog::Logger) -> DeployThread {
         DeployThread {
             deploy_channel: deploy_channel,
             slack_channel: slack_channel,
+            config: config,
+            shutdown: shutdown,
             logger: logger,
         }
     }
@@ -33,8 +89,20 @@ impl DeployThread {
     pub fn call(&self) {
         info!(self.logger, "deploy_thread_starting");
 
+        self._spool_replay();
+
         loop {
-            let data = match self.deploy_channel.recv() {
+            // stop taking deploys, queued ones are spooled by the shutdown
+            if self.shutdown.load(Ordering::SeqCst) {
+                info!(self.logger, "deploy_thread_stopping");
+
+                return ()
+            }
+
+            let data = match self.deploy_channel.recv_timeout(time::Duration::from_millis(DEPLOY_RECV_TIMEOUT_MILLIS)) {
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    continue
+                },
                 Err(e) => {
                     error!(self.logger, "deploy_thread_exception: {}", e);
 
@@ -45,39 +113,107 @@ impl DeployThread {
                 }
             };
 
-            // parse the json string into a DeployMessage object
-            let message: DeployMessage = match serde_json::from_str(&data) {
-                Err(e) => {
-                    error!(self.logger, "deploy_thread_exception: {}", e);
+            self._deploy(&data);
+        }
+    }
 
-                    continue
+    fn _deploy(&self, data: &str) {
+        // parse the json string into a DeployMessage object
+        let message: DeployMessage = match serde_json::from_str(data) {
+            Err(e) => {
+                error!(self.logger, "deploy_thread_exception: {}", e);
+
+                return
+            },
+            Ok(object) => {
+                object
+            }
+        };
+
+        FsTouch::call(&message.id);
+
+        let mut runner = StageRunner::new(
+            message.id.clone(),
+            message.repo.clone(),
+            message.tag.clone(),
+            message.path.clone(),
+            self.config.clone(),
+            StageRunnerHandles {
+                shutdown: self.shutdown.clone(),
+                slack_channel: self.slack_channel.clone(),
+            },
+            self.logger.clone(),
+        );
+
+        let code = match runner.call() {
+            Some(code) => {
+                code
+            },
+            None => {
+                500
+            }
+        };
+
+        runner.finish(code);
+
+        FsRemove::call(&message.id);
+
+        WorkspaceRetain::new(&message.id, &self.config.workspace, self.logger.clone()).call(code);
+        GitMirrorPrune::new(&self.config.git, self.logger.clone()).call();
+
+        // nothing was applied, run it again after the restart
+        if runner.interrupted {
+            match DeploySpool::new().write(&message.id, data) {
+                Ok(path) => {
+                    info!(self.logger, "deploy_spool_write"; "path" => path, "id" => &message.id);
                 },
-                Ok(object) => {
-                    object
+                Err(e) => {
+                    error!(self.logger, "deploy_spool_exception: {}", e; "id" => &message.id);
                 }
             };
+        }
+    }
+
+    //
+    // run deploys spooled by a previous run's shutdown, each file is removed before its deploy
+    // starts so a deploy that crashes deploybot isn't retried forever
+    //
+
+    fn _spool_replay(&self) {
+        let spool = DeploySpool::new();
+
+        let files = match spool.list() {
+            Err(e) => {
+                error!(self.logger, "deploy_spool_exception: {}", e);
+
+                return
+            },
+            Ok(files) => {
+                files
+            }
+        };
 
-            FsTouch::call(&message.id);
+        for path in files.iter() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                return
+            }
 
-            let mut runner = StageRunner::new(
-                message.id.clone(),
-                message.repo,
-                message.tag,
-                message.path,
-                self.logger.clone(),
-                self.slack_channel.clone(),
-            );
+            let data = match fs::read_to_string(path) {
+                Err(e) => {
+                    error!(self.logger, "deploy_spool_exception: {}", e; "path" => path);
 
-            let _code = match runner.call() {
-                Some(code) => {
-                    code
+                    continue
                 },
-                None => {
-                    500
+                Ok(data) => {
+                    data
                 }
             };
 
-            FsRemove::call(&message.id);
+            let _ = fs::remove_file(path);
+
+            info!(self.logger, "deploy_spool_replay"; "path" => path);
+
+            self._deploy(&data);
         }
     }
 }
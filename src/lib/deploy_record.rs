@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Result;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::fs::FsRecord;
+
+//
+// deploy record, written as json as each stage completes so a deploy can be inspected
+// while its running and after its done
+//
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeployRecord {
+    pub id: String,
+    pub repo: String,
+    pub tag: String,
+    pub path: String,
+    pub sha: String,
+    pub author: String,  // deployed commit author, e.g. "Jane Doe <jane@example.com>"
+    pub message: String,  // deployed commit message
+    pub previous_sha: String,  // sha of the last completed deploy of the resource
+    pub changelog: Vec<DeployRecordCommit>,  // commits since previous_sha, or rolled back, newest first
+    pub changelog_truncated: bool,
+    pub changelog_error: String,  // why there is no changelog, e.g. previous_sha is not in a shallow checkout
+    pub rollback: bool,  // sha is an ancestor of previous_sha, the changelog lists the commits rolled back
+    pub image: String,  // image that was built, e.g. gcr.io/project/api:<sha>-<build hash>
+    pub image_digest: String,  // registry digest, e.g. sha256:...
+    pub image_reused: bool,  // image was already in the registry and not rebuilt
+    pub images: Vec<DeployRecordImage>,  // all images built, the first is 'image'
+    pub build_seconds: u64,  // docker stage duration
+    pub cache_hit_rate: f64,  // cached build steps over all build steps, 0 to 1
+    pub state: String,  // running, completed, failed, interrupted, unverified
+    pub code: i32,
+    pub started_at: u64,  // unix seconds
+    pub finished_at: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeployRecordImage {
+    pub name: String,
+    pub image: String,
+    pub image_digest: String,
+    pub image_reused: bool,
+    pub build_seconds: u64,
+    pub build_steps: u32,
+    pub build_cached: u32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeployRecordCommit {
+    pub sha: String,
+    pub author: String,
+    pub summary: String,  // first line of the message
+    pub time: i64,  // unix seconds
+}
+
+#[derive(Debug)]
+pub struct DeployRecordLatest {}
+
+#[derive(Debug)]
+pub struct DeployRecordRead {}
+
+#[derive(Debug)]
+pub struct DeployRecordWrite {}
+
+impl DeployRecord {
+    pub fn new(id: &str, repo: &str, tag: &str, path: &str) -> DeployRecord {
+        DeployRecord {
+            id: id.to_string(),
+            repo: repo.to_string(),
+            tag: tag.to_string(),
+            path: path.to_string(),
+            state: "running".to_string(),
+            started_at: DeployRecord::now(),
+            ..Default::default()
+        }
+    }
+
+    pub fn finish(&mut self, code: i32) {
+        self.code = code;
+        self.state = if code == 0 { "completed".to_string() } else { "failed".to_string() };
+        self.finished_at = DeployRecord::now();
+    }
+
+    // stopped at a safe point by a shutdown, before anything was applied
+    pub fn interrupt(&mut self) {
+        self.code = 503;
+        self.state = "interrupted".to_string();
+        self.finished_at = DeployRecord::now();
+    }
+
+    // applied, but a shutdown stopped watching the rollout, not a changelog or build cache base
+    pub fn unverify(&mut self) {
+        self.code = 0;
+        self.state = "unverified".to_string();
+        self.finished_at = DeployRecord::now();
+    }
+
+    pub fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+impl DeployRecordLatest {
+    // last completed deploy of a resource path in a repo, e.g. "kubernetes/resources.toml:api-staging"
+    pub fn call(repo: &str, path: &str) -> Option<DeployRecord> {
+        let id = fs::read_to_string(FsRecord::latest(repo, path)).ok()?;
+
+        let record = DeployRecordRead::call(id.trim()).ok()?;
+
+        if record.repo != repo || record.path != path {
+            return None
+        }
+
+        Some(record)
+    }
+
+    fn write(record: &DeployRecord) -> Result<()> {
+        let path = FsRecord::latest(&record.repo, &record.path);
+
+        if let Some(dir) = Path::new(&path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let path_tmp = format!("{}.tmp", path);
+
+        fs::write(&path_tmp, &record.id)?;
+        fs::rename(&path_tmp, &path)?;
+
+        Ok(())
+    }
+}
+
+impl DeployRecordRead {
+    pub fn call(id: &str) -> Result<DeployRecord> {
+        let data = fs::read_to_string(FsRecord::call(id))?;
+
+        Ok(serde_json::from_str(&data)?)
+    }
+}
+
+impl DeployRecordWrite {
+    pub fn call(record: &DeployRecord) -> Result<()> {
+        let path = FsRecord::call(&record.id);
+
+        if let Some(dir) = Path::new(&path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // write and rename so readers never see a partial file
+        let path_tmp = format!("{}.tmp", path);
+
+        fs::write(&path_tmp, serde_json::to_string_pretty(record)?)?;
+        fs::rename(&path_tmp, &path)?;
+
+        // the latest index only points at completed deploys
+        if record.state == "completed" {
+            DeployRecordLatest::write(record)?;
+        }
+
+        Ok(())
+    }
+}
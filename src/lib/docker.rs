@@ -1,79 +1,295 @@
-use super::fs::FsRoot;
-use super::kube_resource::KubeResourceParser;
+use super::config::{BuildBackend, Config};
+use super::deploy_record::DeployRecordImage;
+use super::image_builder::{ImageBuildSpec, ImageBuildStats, ImageBuilder};
+use super::kube_resource::{KubeBuild, KubeImage, KubeImageSetConfig, KubeResource};
+use super::registry_auth::{RegistryAuth, RegistryAuthRemove};
+use super::template::{TemplateRender, TemplateVars};
 
-use slog::{error, info};
-use std::io::Result;
-use std::process::{Command, ExitStatus};
+use slog::{error, info, o, warn};
+use std::io::{Error, ErrorKind, Result};
+use std::thread;
+use std::time::Instant;
+
+//
+// build and push the resource's images, optionally in parallel
+//
 
 #[derive(Debug)]
 pub struct DockerStage {
-    pub image_tag: String,
+    pub images: Vec<DockerImage>,  // built images, the first is the resource's primary image
+    pub previous_images: Vec<DeployRecordImage>,  // images of the last completed deploy, used as build cache
     pub id: String,
-    pub resource_file: String,
-    pub resource_key: String,
+    pub resource: KubeResource,
+    pub vars: TemplateVars,
+    pub config: Config,
     pub logger: slog::Logger,
 }
 
-impl DockerStage {
-    pub fn new(id: &String, resource_path: &String, logger: slog::Logger) -> DockerStage {
-        let resource_vec: Vec<_> = resource_path.split(":").collect();
+// image that was built and pushed
+#[derive(Clone, Debug)]
+pub struct DockerImage {
+    pub name: String,
+    pub image_name: String,
+    pub image_tag: String,  // e.g. gcr.io/project/api:<sha>-<build hash>
+    pub image_digest: String,  // e.g. sha256:...
+    pub image_reused: bool,
+    pub build_seconds: u64,
+    pub build_stats: ImageBuildStats,
+    pub placeholder: String,
+    pub image_set: Option<KubeImageSetConfig>,
+}
 
+#[derive(Debug)]
+pub struct DockerImageBuild {
+    pub image_tag: String,
+    pub image_digest: String,  // e.g. sha256:...
+    pub image_reused: bool,  // image for the same sha and build options was already in the registry
+    pub build_seconds: u64,
+    pub build_stats: ImageBuildStats,
+    pub cache_from: Vec<String>,
+    pub image: KubeImage,
+    pub build: KubeBuild,
+    pub builder: ImageBuilder,
+    pub pin_digest: bool,
+    pub vars: TemplateVars,
+    pub config: Config,
+    pub logger: slog::Logger,
+}
+
+impl DockerStage {
+    pub fn new(id: &String, resource: &KubeResource, vars: &TemplateVars, previous_images: &[DeployRecordImage], config: &Config, logger: slog::Logger) -> DockerStage {
         DockerStage {
-            image_tag: "".to_owned(),
+            images: Vec::new(),
+            previous_images: previous_images.to_vec(),
             id: id.to_owned(),
-            resource_file: resource_vec[0].to_owned(),
-            resource_key: resource_vec[1].to_owned(),
+            resource: resource.clone(),
+            vars: vars.clone(),
+            config: config.clone(),
             logger: logger,
         }
     }
 
     pub fn call(&mut self) -> Option<i32> {
-        let toml_file = format!(
-            "{}/{}",
-            FsRoot::call(&self.id),
-            &self.resource_file,
-        );
-
-        let resource_parser = KubeResourceParser::new(
-            &toml_file,
-            &self.resource_key,
-        );
-
-        let resource = match resource_parser.call() {
-            Some(value) => {
-                value
+        match RegistryAuth::new(&self.id, &self.config, self.logger.clone()).call() {
+            Some(0) => {},
+            code => {
+                return code
+            }
+        };
+
+        let code = self._images_build();
+
+        // credentials are only needed while building
+        RegistryAuthRemove::call(&self.id);
+
+        code
+    }
+
+    fn _images_build(&mut self) -> Option<i32> {
+        let mut builds: Vec<DockerImageBuild> = self.resource.images_list().iter()
+            .map(|image| {
+                let mut build = DockerImageBuild::new(&self.id, image, self.resource.pin_digest, &self.vars, &self.config, self.logger.clone());
+
+                if build.build.cache_from_previous {
+                    build.cache_from = self.previous_images.iter()
+                        .filter(|previous| previous.name == image.name && !previous.image.is_empty())
+                        .map(|previous| previous.image.to_string())
+                        .collect();
+                }
+
+                build
+            })
+            .collect();
+
+        let codes: Vec<Option<i32>> = if self.resource.parallel_builds && builds.len() > 1 {
+            thread::scope(|scope| {
+                let handles: Vec<_> = builds.iter_mut()
+                    .map(|build| scope.spawn(move || build.call()))
+                    .collect();
+
+                handles.into_iter().map(|handle| handle.join().unwrap_or(Some(500))).collect()
+            })
+        } else {
+            let mut codes = Vec::new();
+
+            // stop at the first failed build
+            for build in builds.iter_mut() {
+                let code = build.call();
+
+                codes.push(code);
+
+                if code != Some(0) {
+                    break
+                }
+            }
+
+            codes
+        };
+
+        // first failed build code, if any
+        match codes.into_iter().find(|code| *code != Some(0)) {
+            Some(code) => {
+                return code
+            },
+            None => {}
+        };
+
+        self.images = builds.iter().map(|build| DockerImage {
+            name: build.image.name.to_string(),
+            image_name: build.image.image_name.to_string(),
+            image_tag: build.image_tag.to_string(),
+            image_digest: build.image_digest.to_string(),
+            image_reused: build.image_reused,
+            build_seconds: build.build_seconds,
+            build_stats: build.build_stats.clone(),
+            placeholder: build.image.placeholder.clone().unwrap_or_default(),
+            image_set: build.image.image_set.clone(),
+        }).collect();
+
+        Some(0)
+    }
+}
+
+impl DockerImage {
+    // image reference for manifests, pinned to the digest when known
+    pub fn reference(&self, pin_digest: bool) -> String {
+        if pin_digest && !self.image_digest.is_empty() {
+            return format!("{}@{}", self.image_name, self.image_digest)
+        }
+
+        self.image_tag.to_string()
+    }
+
+    // tag part of the image tag, e.g. <sha>-<build hash>
+    pub fn version(&self) -> &str {
+        self.image_tag.strip_prefix(&format!("{}:", self.image_name)).unwrap_or("")
+    }
+}
+
+impl DockerImageBuild {
+    pub fn new(id: &String, image: &KubeImage, pin_digest: bool, vars: &TemplateVars, config: &Config, logger: slog::Logger) -> DockerImageBuild {
+        let build = image.build.clone().unwrap_or_default();
+        let backend = build.builder.clone().unwrap_or(config.docker.builder.clone());
+        let builder = ImageBuilder::new(&backend, id, config);
+
+        DockerImageBuild {
+            image_tag: "".to_owned(),
+            image_digest: "".to_owned(),
+            image_reused: false,
+            build_seconds: 0,
+            build_stats: ImageBuildStats::default(),
+            cache_from: Vec::new(),
+            build: build,
+            builder: builder,
+            image: image.clone(),
+            pin_digest: pin_digest,
+            vars: vars.clone(),
+            config: config.clone(),
+            logger: logger.new(o!("image" => image.name.to_string(), "builder" => backend.to_string())),
+        }
+    }
+
+    pub fn call(&mut self) -> Option<i32> {
+        let docker_file = self.image.docker_file.clone();
+        let image_name = self.image.image_name.clone();
+
+        // a resource can select the docker builder when deploybot defaults to a daemonless one
+        if self.builder.backend == BuildBackend::Docker && self.config.docker.host_uri.is_empty() {
+            error!(self.logger, "docker_build_exception: docker builder requires docker.host_uri");
+
+            return Some(400)
+        }
+
+        // tag by git sha and build options, so the same source is only built once
+        let image_version = match self._image_version(&docker_file) {
+            Ok(version) => {
+                version
             },
-            None => {
+            Err(e) => {
+                error!(self.logger, "docker_build_exception: {}", e);
+
                 return Some(400)
             }
         };
 
-        let docker_file = match resource.get("docker_file") {
-            Some(s) => {
-                s.as_str().unwrap().to_string()  // remove string quotes
+        self.image_tag = format!("{}:{}", image_name, image_version);
+
+        // the digest is read from the registry, a reused image is never pulled
+        if !self.build.rebuild {
+            match self.builder.exists(&self.image_tag) {
+                Ok(true) => {
+                    info!(self.logger, "docker_image_reused"; "tag" => &self.image_tag);
+
+                    self.image_reused = true;
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    // build it, an existing image is overwritten with the same source
+                    warn!(self.logger, "docker_image_exists_exception: {}", e; "tag" => &self.image_tag);
+                }
+            };
+        }
+
+        if !self.image_reused {
+            match self._build_push(&docker_file) {
+                Some(0) => {},
+                code => {
+                    return code
+                }
+            };
+        }
+
+        // digest of the pushed image, so manifests can pin exactly what was built
+        self.image_digest = match self.builder.digest(&self.image_tag) {
+            Ok(digest) => {
+                info!(self.logger, "docker_digest_ok"; "tag" => &self.image_tag, "digest" => &digest);
+
+                digest
             },
-            None => {
-                return Some(400)
+            Err(e) => {
+                // a pinned resource never silently falls back to the tag reference
+                if self.pin_digest {
+                    error!(self.logger, "docker_digest_exception: {}", e; "tag" => &self.image_tag);
+
+                    return Some(500)
+                }
+
+                // manifests keep the tag reference, the image was pushed
+                warn!(self.logger, "docker_digest_exception: {}", e; "tag" => &self.image_tag);
+
+                "".to_owned()
             }
         };
 
-        let image_name = match resource.get("image_name") {
-            Some(s) => {
-                s.as_str().unwrap().to_string() // remove string quotes
+        Some(0)
+    }
+
+    fn _build_push(&mut self, docker_file: &String) -> Option<i32> {
+        let build_spec = match self._build_spec(&docker_file, &self.image_tag) {
+            Ok(args) => {
+                args
             },
-            None => {
+            Err(e) => {
+                error!(self.logger, "docker_build_exception: {}", e);
+
                 return Some(400)
             }
         };
 
-        self.image_tag = format!("{}:{}", image_name, self.id);
+        let started = Instant::now();
 
-        let docker_uri = dotenv::var("DOCKER_HOST_URI").unwrap();
+        match self.builder.build(&build_spec) {
+            Ok((status, stats)) => {
+                self.build_seconds = started.elapsed().as_secs();
+                self.build_stats = stats;
 
-        match self._docker_build(&docker_uri, &docker_file, &self.image_tag) {
-            Ok(status) => {
                 if status.success() {
-                    info!(self.logger, "docker_build_ok"; "tag" => &self.image_tag);
+                    info!(self.logger, "docker_build_ok";
+                        "tag" => &self.image_tag,
+                        "seconds" => self.build_seconds,
+                        "steps" => self.build_stats.steps,
+                        "cached" => self.build_stats.cached,
+                    );
                 } else {
                     error!(self.logger, "docker_build_exception: {}", status);
 
@@ -87,7 +303,13 @@ impl DockerStage {
             }
         };
 
-        match self._docker_push(&docker_uri, &self.image_tag) {
+        if self.builder.pushes_on_build() {
+            info!(self.logger, "docker_push_ok"; "tag" => &self.image_tag, "builder" => self.builder.backend.to_string());
+
+            return Some(0)
+        }
+
+        match self.builder.push(&self.image_tag) {
             Ok(status) => {
                 if status.success() {
                     info!(self.logger, "docker_push_ok"; "tag" => &self.image_tag);
@@ -107,22 +329,103 @@ impl DockerStage {
         Some(0)
     }
 
-    fn _docker_build(&self, docker_uri: &String, docker_file: &String, image_tag: &String) -> Result<ExitStatus> {
-        let status = Command::new("docker")
-            .args(&["--host", &docker_uri, "build", "-f", &docker_file, "-t", &image_tag, "."])
-            .current_dir(FsRoot::call(&self.id))
-            .status()?;
+    //
+    // image version is '<git sha>-<build hash>', the hash covers everything besides the source
+    // that changes the image: docker file, build args, labels, target, platform, context and secret ids
+    //
+
+    fn _image_version(&self, docker_file: &String) -> Result<String> {
+        let build = &self.build;
+
+        let mut parts = vec![
+            format!("docker_file={}", docker_file),
+            format!("target={}", build.target.clone().unwrap_or_default()),
+            format!("platform={}", build.platform.clone().unwrap_or_default()),
+            format!("context={}", build.context.clone().unwrap_or_default()),
+        ];
 
-        Ok(status)
+        for (key, value) in build.args.iter() {
+            parts.push(format!("arg.{}={}", key, self._render(key, value)?));
+        }
+
+        for (key, value) in build.labels.iter() {
+            parts.push(format!("label.{}={}", key, self._render(key, value)?));
+        }
+
+        for secret in build.secrets.iter() {
+            parts.push(format!("secret.{}={}", secret.id, secret.source));
+        }
+
+        let hash: String = openssl::sha::sha256(parts.join("\n").as_bytes()).iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        let sha = &self.vars["git_sha"];
+
+        if sha.len() < 12 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid git sha '{}'", sha)))
+        }
+
+        Ok(format!("{}-{}", &sha[..12], &hash[..12]))
     }
 
-    fn _docker_push(&self, docker_uri: &String, image_tag: &String) -> Result<ExitStatus> {
-        let status = Command::new("docker")
-            .args(&["--host", &docker_uri, "push", &image_tag])
-            .current_dir(FsRoot::call(&self.id))
-            .status()?;
+    // build options for the image builder, with rendered args and labels and secret files
+    fn _build_spec(&self, docker_file: &String, image_tag: &String) -> Result<ImageBuildSpec> {
+        let build = &self.build;
+
+        let mut spec = ImageBuildSpec {
+            docker_file: docker_file.to_string(),
+            image_tag: image_tag.to_string(),
+            context: build.context.clone().unwrap_or(".".to_string()),
+            target: build.target.clone(),
+            platform: build.platform.clone(),
+            cache_from: self.cache_from.clone(),
+            cache_ref: build.cache_ref.clone(),
+            ..Default::default()
+        };
+
+        for (key, value) in build.args.iter() {
+            spec.args.push((key.to_string(), self._render(key, value)?));
+        }
+
+        // default oci labels, resource labels can override them, no tag derived label as images
+        // are reused across tags of the same commit
+        let mut labels = std::collections::BTreeMap::new();
 
-        Ok(status)
+        labels.insert("org.opencontainers.image.revision".to_string(), self.vars["git_sha"].to_string());
+        labels.insert("org.opencontainers.image.source".to_string(), self.vars["git_repo"].to_string());
+
+        for (key, value) in build.labels.iter() {
+            labels.insert(key.to_string(), self._render(key, value)?);
+        }
+
+        spec.labels = labels.into_iter().collect();
+
+        for secret in build.secrets.iter() {
+            let file = match self.config.docker.secrets.get(&secret.source) {
+                None => {
+                    return Err(Error::new(ErrorKind::NotFound, format!("build secret '{}' is not configured", secret.source)))
+                },
+                Some(file) => {
+                    file
+                }
+            };
+
+            spec.secrets.push((secret.id.to_string(), file.to_string()));
+        }
+
+        Ok(spec)
+    }
+
+    fn _render(&self, key: &str, value: &str) -> Result<String> {
+        match TemplateRender::call(value, &self.vars, true) {
+            Err(e) => {
+                Err(Error::new(ErrorKind::InvalidInput, format!("build option {}: {}", key, e)))
+            },
+            Ok(value) => {
+                Ok(value)
+            }
+        }
     }
 
 }
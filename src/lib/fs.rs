@@ -1,17 +1,144 @@
 use std::fs;
 use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use std::{thread, time};
 
 const DEPLOYBOT_TMP_DIR: &str = "/var/tmp/deploybot";
 
+// workspace.dir from the config, set once at startup
+static WORKSPACE_DIR: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct FsDockerConfig {}
+
+// lock file, removed when dropped
+#[derive(Debug)]
+pub struct FsLock {
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub struct FsQueue {}
+
+#[derive(Debug)]
+pub struct FsRecord {}
+
 #[derive(Debug)]
 pub struct FsRemove {}
 
 #[derive(Debug)]
 pub struct FsRoot {}
 
+#[derive(Debug)]
+pub struct FsTmp {}
+
 #[derive(Debug)]
 pub struct FsTouch {}
 
+// registry credentials for a deploy, kept outside the checkout so they are never in a build context
+impl FsDockerConfig {
+    pub fn call(id: &str) -> String {
+        format!("{}/{}", FsDockerConfig::dir(), id)
+    }
+
+    pub fn dir() -> String {
+        format!("{}/docker", DEPLOYBOT_TMP_DIR)
+    }
+
+    // the host's docker config dir, DOCKER_CONFIG or ~/.docker, as the docker cli reads it
+    pub fn ambient() -> Option<String> {
+        match std::env::var("DOCKER_CONFIG") {
+            Ok(dir) => {
+                Some(dir)
+            },
+            Err(_) => {
+                Some(format!("{}/.docker", std::env::var("HOME").ok()?))
+            }
+        }
+    }
+}
+
+impl FsLock {
+    //
+    // create the lock file, waiting for another holder to remove it, a lock older than
+    // 'stale' is left over from a crashed process and is taken over
+    //
+
+    pub fn call(path: &str, wait: Duration, stale: Duration) -> Result<FsLock> {
+        let started = SystemTime::now();
+
+        loop {
+            match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(_) => {
+                    return Ok(FsLock { path: path.to_string() })
+                },
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {},
+                Err(e) => {
+                    return Err(e)
+                }
+            };
+
+            let age = fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+
+            if age.map(|age| age > stale) == Some(true) {
+                let _ = fs::remove_file(path);
+
+                continue
+            }
+
+            if started.elapsed().map(|elapsed| elapsed > wait).unwrap_or(true) {
+                return Err(Error::new(ErrorKind::TimedOut, format!("lock {} is held", path)))
+            }
+
+            thread::sleep(time::Duration::from_millis(500));
+        }
+    }
+
+    // update the lock mtime so a long running holder isn't taken for stale
+    pub fn refresh(path: &str) {
+        if let Ok(file) = fs::OpenOptions::new().write(true).open(path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    }
+}
+
+impl Drop for FsLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// deploys that were queued, or interrupted before they were applied, at shutdown
+impl FsQueue {
+    pub fn dir() -> String {
+        format!("{}/queue", DEPLOYBOT_TMP_DIR)
+    }
+}
+
+impl FsRecord {
+    pub fn call(id: &str) -> String {
+        format!("{}/{}.json", FsRecord::dir(), id)
+    }
+
+    pub fn dir() -> String {
+        format!("{}/records", DEPLOYBOT_TMP_DIR)
+    }
+
+    // id of the last completed deploy of a repo and resource path
+    pub fn latest(repo: &str, path: &str) -> String {
+        let hash: String = openssl::sha::sha256(format!("{}\n{}", repo, path).as_bytes()).iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        format!("{}/latest/{}", FsRecord::dir(), &hash[..32])
+    }
+}
+
 impl FsRemove {
     pub fn call(id: &str) -> Option<u32> {
         let path = format!("{}.txt", FsRoot::call(id));
@@ -25,8 +152,24 @@ impl FsRemove {
 }
 
 impl FsRoot {
+    pub fn init(dir: &str) {
+        let _ = WORKSPACE_DIR.set(dir.trim_end_matches('/').to_string());
+    }
+
     pub fn call(id: &str) -> String {
-        format!("{}/{}", DEPLOYBOT_TMP_DIR, id)
+        format!("{}/{}", FsRoot::dir(), id)
+    }
+
+    // e.g. the validate subcommand runs without a config
+    pub fn dir() -> String {
+        WORKSPACE_DIR.get().map(|dir| dir.to_string()).unwrap_or(DEPLOYBOT_TMP_DIR.to_string())
+    }
+}
+
+// queue, records, registry credentials and by default slack messages and git mirrors
+impl FsTmp {
+    pub fn dir() -> String {
+        DEPLOYBOT_TMP_DIR.to_string()
     }
 }
 
@@ -1,12 +1,34 @@
-use super::fs::FsRoot;
+use super::config::{Config, GitConfig, GitRefPolicyConfig};
+use super::fs::{FsLock, FsRoot};
+use super::git_auth::{GitAuth, GitCredential};
+use super::git_ref_policy::GitRefPolicy;
+use super::git_repo::{GitRepoFind, GitRepoNormalize};
+use super::git_verify::GitVerify;
+use super::kube_resource::{KubeResource, KubeResourceParser, KubeResourceResolve};
 
-use dotenv;
-use git2::{Cred, Oid, RemoteCallbacks, Repository};
-use slog::error;
-use std::env;
+use git2::{Oid, RemoteCallbacks, Repository};
+use slog::{error, info, warn};
+use std::fs;
 use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
 
-// const GIT_SSH_KEY: &str = ".ssh/id_rsa";
+// refs kept in a mirror or fetched into a workspace, deploys resolve branches, tags and shas
+const FETCH_REFSPECS: [&str; 2] = ["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"];
+const MIRROR_BRANCHES_REFSPEC: &str = "+refs/heads/*:refs/remotes/origin/*";
+
+// libgit2 depth that fetches the full history of a shallow repo
+const FETCH_DEPTH_UNSHALLOW: i32 = i32::MAX;
+
+// libgit2 retries the credentials callback while authentication fails
+const CREDENTIAL_ATTEMPTS_MAX: usize = 3;
+
+// a fetch of a large repo can take minutes, a lock this old was left by a crashed deploy
+const MIRROR_LOCK_WAIT: Duration = Duration::from_secs(600);
+const MIRROR_LOCK_STALE: Duration = Duration::from_secs(1800);
+const MIRROR_LOCK_TOUCH: Duration = Duration::from_secs(60);
+
+const MIRROR_KEEP_DAY: u64 = 86400;
 
 #[derive(Debug)]
 pub struct GitStage {
@@ -14,22 +36,58 @@ pub struct GitStage {
     pub repo: String,
     pub sha: String,
     pub tag: String,
+    pub path: String,  // resource path, e.g. kubernetes/resources.toml:api-staging
+    pub credential: Option<GitCredential>,
+    pub error: Option<String>,  // reported to slack, e.g. an unsigned tag
+    pub config: Config,
+    pub logger: slog::Logger,
+}
+
+#[derive(Debug)]
+pub struct GitMirrorPrune {
+    pub config: GitConfig,
     pub logger: slog::Logger,
 }
 
 impl GitStage {
-    pub fn new(id: &String, repo: &String, tag: &String, logger: slog::Logger) -> GitStage {
+    pub fn new(id: &String, repo: &String, tag: &String, path: &String, config: &Config, logger: slog::Logger) -> GitStage {
         GitStage {
             id: id.to_owned(),
             repo: repo.to_owned(),
             sha: "".to_owned(),
             tag: tag.to_owned(),
+            path: path.to_owned(),
+            credential: None,
+            error: None,
+            config: config.clone(),
             logger: logger,
         }
     }
 
+    //
+    // clone without checking out, from the mirror or as a shallow fetch, resolve the tag and
+    // check out the commit, only the resource's paths if it sets sparse_paths
+    //
+
     pub fn call(&mut self) -> Option<i32> {
-        match self._git_clone_init() {
+        self.credential = match GitAuth::new(&self.repo, &self.config.git).call() {
+            Ok(credential) => {
+                Some(credential)
+            },
+            Err(e) => {
+                error!(self.logger, "git auth error: {}", e; "host" => GitAuth::host(&self.repo));
+
+                return Some(400)
+            }
+        };
+
+        let clone_result = if self.config.git.mirror {
+            self._git_mirror_clone()
+        } else {
+            self._git_fetch_init()
+        };
+
+        match clone_result {
             Ok(_) => {},
             Err(e) => {
                 error!(self.logger, "git clone error: {}", e);
@@ -42,6 +100,19 @@ impl GitStage {
             Ok(sha) => {
                 sha
             },
+            Err(_) if !self.config.git.mirror => {
+                // e.g. a short sha or revspec, fetch the history of all branches and tags
+                match self._git_fetch_all(FETCH_DEPTH_UNSHALLOW).and_then(|_| self._git_revparse()) {
+                    Ok(sha) => {
+                        sha
+                    },
+                    Err(e) => {
+                        error!(self.logger, "git revparse error: {}", e);
+
+                        return Some(400)
+                    }
+                }
+            },
             Err(e) => {
                 error!(self.logger, "git revparse error: {}", e);
 
@@ -49,7 +120,90 @@ impl GitStage {
             }
         };
 
-        match self._git_checkout_commit() {
+        // from the deploybot config, never from the commit being deployed
+        if let Some(refs) = self._git_ref_policy() {
+            // reachability from a protected branch needs the history
+            if !self.config.git.mirror && !refs.protected_branches.is_empty() {
+                match self._git_fetch_all(FETCH_DEPTH_UNSHALLOW) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        error!(self.logger, "git fetch error: {}", e);
+
+                        return Some(400)
+                    }
+                };
+            }
+
+            match GitRefPolicy::new(&self.id, &self.tag, &self.sha, &refs).call() {
+                Ok(allowed) => {
+                    info!(self.logger, "git_ref_policy_ok"; "allowed" => allowed);
+                },
+                Err(e) => {
+                    error!(self.logger, "git ref policy error: {}", e);
+
+                    self.error = Some(e.to_string());
+
+                    return Some(400)
+                }
+            };
+        }
+
+        // resource checkout options, from the resources file at the deploy commit
+        let resource = self._git_resource();
+
+        // from the deploybot config, the deploy commit can't opt itself out
+        if self._git_require_signed() {
+            match GitVerify::new(&self.id, &self.tag, &self.sha, &self.config.git).call() {
+                Ok(verified) => {
+                    info!(self.logger, "git_signature_ok"; "verified" => verified);
+                },
+                Err(e) => {
+                    error!(self.logger, "git signature error: {}", e);
+
+                    self.error = Some(e.to_string());
+
+                    return Some(400)
+                }
+            };
+        }
+
+        let git_depth = resource.as_ref().and_then(|resource| resource.git_depth);
+
+        if self.config.git.mirror {
+            // mirrors keep the full history, workspaces are local clones of them
+            if let Some(depth) = git_depth {
+                info!(self.logger, "git_depth_ignored: mirror has the full history"; "depth" => depth);
+            }
+        } else {
+            let depth = git_depth.unwrap_or(FETCH_DEPTH_UNSHALLOW);
+
+            if depth > 1 {
+                match self._git_fetch(&self._git_request_refspecs(), depth) {
+                    Ok(_) => {},
+                    Err(e) => {
+                        error!(self.logger, "git fetch error: {}", e);
+
+                        return Some(400)
+                    }
+                };
+            }
+        }
+
+        let paths = match &resource {
+            Some(resource) if !resource.sparse_paths.is_empty() => {
+                let mut paths = resource.checkout_paths();
+                paths.push(self._resource_file());
+
+                info!(self.logger, "git_sparse_checkout"; "paths" => paths.join(","));
+
+                Some(paths)
+            },
+            _ => {
+                None
+            }
+        };
+
+        match self._git_checkout_commit(&paths) {
             Ok(_) => {},
             Err(e) => {
                 error!(self.logger, "git checkout error: {}", e);
@@ -58,14 +212,131 @@ impl GitStage {
             }
         };
 
+        if resource.as_ref().map(|resource| resource.git_submodules) == Some(true) {
+            let result = Repository::open(&FsRoot::call(&self.id)).and_then(|repo| self._git_submodules_update(&repo, &paths));
+
+            match result {
+                Ok(_) => {
+                    info!(self.logger, "git_submodules_ok");
+                },
+                Err(e) => {
+                    error!(self.logger, "git submodule error: {}", e);
+
+                    return Some(400)
+                }
+            };
+        }
+
+        if resource.as_ref().map(|resource| resource.git_lfs) == Some(true) {
+            match self._git_lfs_pull(&paths) {
+                Ok(_) => {
+                    info!(self.logger, "git_lfs_ok");
+                },
+                Err(e) => {
+                    error!(self.logger, "git lfs error: {}", e);
+
+                    return Some(400)
+                }
+            };
+        }
+
         Some(0)
     }
 
-    fn _git_checkout_commit(&self) -> Result<(), git2::Error> {
+    //
+    // init and update submodules recursively, with the credentials for each submodule's host,
+    // a sparse checkout only updates submodules in or above its paths
+    //
+
+    fn _git_submodules_update(&self, repo: &Repository, paths: &Option<Vec<String>>) -> Result<(), git2::Error> {
+        for mut submodule in repo.submodules()? {
+            if let Some(paths) = paths {
+                let included = paths.iter()
+                    .map(|path| Path::new(path.trim_end_matches('/')))
+                    .any(|path| submodule.path().starts_with(path) || path.starts_with(submodule.path()));
+
+                if !included {
+                    info!(self.logger, "git_submodule_skipped"; "path" => submodule.path().to_string_lossy().to_string());
+
+                    continue
+                }
+            }
+
+            submodule.init(false)?;
+
+            // relative urls are resolved against origin by init
+            let url = repo.config()?
+                .get_string(&format!("submodule.{}.url", submodule.name().unwrap_or("")))
+                .unwrap_or(submodule.url().unwrap_or("").to_string());
+
+            let credential = match GitAuth::new(&url, &self.config.git).call() {
+                Ok(credential) => {
+                    Some(credential)
+                },
+                Err(e) => {
+                    return Err(git2::Error::from_str(&format!("submodule {} auth: {}", url, e)))
+                }
+            };
+
+            let mut update_options = git2::SubmoduleUpdateOptions::new();
+            update_options.fetch(self._git_fetch_options_credential(credential));
+
+            submodule.update(true, Some(&mut update_options))?;
+
+            self._git_submodules_update(&submodule.open()?, &None)?;
+        }
+
+        Ok(())
+    }
+
+    //
+    // libgit2 has no lfs support, run 'git lfs pull' for the checked out paths, credentials are
+    // passed in the environment and config so they never appear in the remote url or logs
+    //
+
+    fn _git_lfs_pull(&self, paths: &Option<Vec<String>>) -> std::io::Result<()> {
+        let mut command = Command::new("git");
+
+        command.current_dir(FsRoot::call(&self.id));
+
+        match &self.credential {
+            Some(GitCredential::Token(username, token)) => {
+                let basic = openssl::base64::encode_block(format!("{}:{}", username, token).as_bytes());
+
+                // git reads -c config from the environment, keeping the token out of the process args
+                command.env("GIT_CONFIG_COUNT", "1")
+                    .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+                    .env("GIT_CONFIG_VALUE_0", format!("Authorization: Basic {}", basic));
+            },
+            Some(GitCredential::SshKey(_, key_file)) => {
+                // GIT_SSH_COMMAND is run by a shell
+                let key_file = format!("'{}'", key_file.replace('\'', "'\\''"));
+
+                command.env("GIT_SSH_COMMAND", format!("ssh -i {} -o IdentitiesOnly=yes", key_file));
+            },
+            _ => {}
+        };
+
+        command.args(&["lfs", "pull"]);
+
+        if let Some(paths) = paths {
+            command.arg(format!("--include={}", paths.join(",")));
+        }
+
+        let status = command.status()?;
+
+        if !status.success() {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("git lfs pull {}", status)))
+        }
+
+        Ok(())
+    }
+
+    fn _git_checkout_commit(&self, paths: &Option<Vec<String>>) -> Result<(), git2::Error> {
         let repo = Repository::open(&FsRoot::call(&self.id))?;
 
-        let oid = Oid::from_str(&self.sha).unwrap();
-        let commit = repo.find_commit(oid).unwrap();
+        let oid = Oid::from_str(&self.sha)?;
+        let commit = repo.find_commit(oid)?;
 
         let _branch = repo.branch(
             &self.sha,
@@ -74,66 +345,399 @@ impl GitStage {
         );
 
         let head_sha = format!("refs/heads/{}", self.sha);
-        let obj = repo.revparse_single(&head_sha).unwrap();
+        let obj = repo.revparse_single(&head_sha)?;
 
-        repo.checkout_tree(&obj, None)?;
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force();
+
+        if let Some(paths) = paths {
+            for path in paths.iter() {
+                checkout.path(path);
+            }
+        }
+
+        repo.checkout_tree(&obj, Some(&mut checkout))?;
 
         repo.set_head(&head_sha)?;
 
         Ok(())
     }
 
-    fn _git_clone_init(&self) -> Result<(), git2::Error> {
-        // git ssh callback
+    // resources file relative to the checkout, e.g. kubernetes/resources.toml
+    fn _resource_file(&self) -> String {
+        self.path.split(':').next().unwrap_or("").to_string()
+    }
+
+    // refs policy of the deployed resource in the repo's allowlist entry, none for other repos
+    fn _git_ref_policy(&self) -> Option<GitRefPolicyConfig> {
+        let resource_key = KubeResourceResolve::key(&self.path)?;
+
+        GitRepoFind::call(&self.config.git, &self.repo)?.refs.get(&resource_key).cloned()
+    }
+
+    // every resource, or the deployed resource in the repo's allowlist entry
+    fn _git_require_signed(&self) -> bool {
+        if self.config.git.require_signed {
+            return true
+        }
+
+        match (KubeResourceResolve::key(&self.path), GitRepoFind::call(&self.config.git, &self.repo)) {
+            (Some(resource_key), Some(git_repo)) => {
+                git_repo.require_signed.contains(&resource_key)
+            },
+            _ => {
+                false
+            }
+        }
+    }
+
+    // check out only the resources file and parse the resource, errors are reported once
+    // the full checkout is parsed by the runner
+    fn _git_resource(&self) -> Option<KubeResource> {
+        let resource_key = KubeResourceResolve::key(&self.path)?;
+
+        let repo = Repository::open(&FsRoot::call(&self.id)).ok()?;
+        let commit = repo.find_commit(Oid::from_str(&self.sha).ok()?).ok()?;
+
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force().path(self._resource_file());
+
+        repo.checkout_tree(commit.as_object(), Some(&mut checkout)).ok()?;
+
+        KubeResourceParser::new(&KubeResourceResolve::call(&self.id, &self.path), &resource_key).call().ok()
+    }
+
+    // clone options that skip the checkout, it is done once the commit is resolved
+    fn _git_checkout_none(&self) -> git2::build::CheckoutBuilder<'static> {
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.dry_run();
+
+        checkout
+    }
+
+    fn _git_fetch_options(&self) -> git2::FetchOptions<'static> {
+        self._git_fetch_options_credential(self.credential.clone())
+    }
+
+    fn _git_fetch_options_credential(&self, credential: Option<GitCredential>) -> git2::FetchOptions<'static> {
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.remote_callbacks(self._git_callbacks(credential));
+
+        fetch_options
+    }
+
+    fn _git_callbacks(&self, credential: Option<GitCredential>) -> RemoteCallbacks<'static> {
+        let mut attempts = 0;
+
+        // git credentials callback, ssh key or agent, or https token
         let mut callbacks = RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, _allowed_types| {
-            Cred::ssh_key(
-                username_from_url.unwrap(),
-                None,
-                std::path::Path::new(&format!("{}/{}", env::var("HOME").unwrap(), dotenv::var("GIT_SSH_KEY").unwrap())),
-                None,
-            )
+        callbacks.credentials(move |_url, _username_from_url, allowed_types| {
+            attempts += 1;
+
+            match &credential {
+                Some(credential) if attempts <= CREDENTIAL_ATTEMPTS_MAX => {
+                    credential.cred(allowed_types)
+                },
+                Some(_) => {
+                    Err(git2::Error::from_str("git authentication failed"))
+                },
+                None => {
+                    Err(git2::Error::from_str("git credential not resolved"))
+                }
+            }
         });
 
+        callbacks
+    }
+
+    // remote HEAD, read while connected as libgit2 only knows it during a connection
+    fn _git_default_branch(&self, remote: &mut git2::Remote) -> Option<String> {
+        let connection = match remote.connect_auth(git2::Direction::Fetch, Some(self._git_callbacks(self.credential.clone())), None) {
+            Ok(connection) => {
+                connection
+            },
+            Err(e) => {
+                warn!(self.logger, "git_default_branch_exception: {}", e; "repo" => &self.repo);
+
+                return None
+            }
+        };
+
+        match connection.default_branch() {
+            Ok(default_branch) => {
+                default_branch.as_str().map(|name| name.to_string())
+            },
+            Err(e) => {
+                // e.g. an empty repo
+                warn!(self.logger, "git_default_branch_exception: {}", e; "repo" => &self.repo);
+
+                None
+            }
+        }
+    }
+
+    // workspace with the requested commit only, deepened once the resource is known
+    fn _git_fetch_init(&self) -> Result<(), git2::Error> {
+        let repo = Repository::init(&FsRoot::call(&self.id))?;
+        repo.remote("origin", &self.repo)?;
+
+        self._git_fetch(&self._git_request_refspecs(), 1)
+    }
+
+    //
+    // refspecs for the requested tag, branch or full sha, revspecs like 'v1.0~1' need all
+    // branches and tags
+    //
+
+    fn _git_request_refspecs(&self) -> Vec<String> {
+        if self.tag.len() == 40 && self.tag.chars().all(|c| c.is_ascii_hexdigit()) {
+            return vec![self.tag.to_string()]
+        }
+
+        if git2::Reference::is_valid_name(&format!("refs/tags/{}", self.tag)) && !self.tag.contains(['~', '^', '@']) {
+            return vec![
+                format!("+refs/tags/{}:refs/tags/{}", self.tag, self.tag),
+                format!("+refs/heads/{}:refs/heads/{}", self.tag, self.tag),
+            ]
+        }
+
+        FETCH_REFSPECS.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn _git_fetch_all(&self, depth: i32) -> Result<(), git2::Error> {
+        let refspecs: Vec<String> = FETCH_REFSPECS.iter().map(|s| s.to_string()).collect();
+
+        self._git_fetch(&refspecs, depth)
+    }
+
+    fn _git_fetch(&self, refspecs: &[String], depth: i32) -> Result<(), git2::Error> {
+        let repo = Repository::open(&FsRoot::call(&self.id))?;
+        let mut remote = repo.find_remote("origin")?;
+
+        let default_branch = self._git_default_branch(&mut remote);
+
+        let mut fetch_options = self._git_fetch_options();
+
+        // a full repo has nothing to unshallow
+        if depth != FETCH_DEPTH_UNSHALLOW || repo.is_shallow() {
+            fetch_options.depth(depth);
+        }
+
+        match remote.fetch(refspecs, Some(&mut fetch_options), None) {
+            Ok(_) => {},
+            Err(e) if depth != FETCH_DEPTH_UNSHALLOW => {
+                // e.g. local transports don't support shallow fetches
+                info!(self.logger, "git_shallow_fetch_exception: {}", e; "depth" => depth);
+
+                let mut fetch_options = self._git_fetch_options();
+                remote.fetch(refspecs, Some(&mut fetch_options), None)?;
+            },
+            Err(e) => {
+                return Err(e)
+            }
+        };
+
+        // like a clone, HEAD is the remote default branch
+        if let Some(name) = default_branch {
+            repo.set_head(&name)?;
+        }
+
+        Ok(())
+    }
+
+    //
+    // fetch into a bare mirror of the repo, then clone the deploy workspace from it locally,
+    // the mirror is locked so concurrent deploys of the same repo don't fetch over each other
+    //
+
+    fn _git_mirror_clone(&self) -> Result<(), git2::Error> {
+        let mirror_path = self._git_mirror_path();
+
+        match fs::create_dir_all(&self.config.git.mirror_dir) {
+            Ok(_) => {},
+            Err(e) => {
+                return Err(git2::Error::from_str(&format!("mirror dir {}: {}", self.config.git.mirror_dir, e)))
+            }
+        };
+
+        let lock = match FsLock::call(&format!("{}.lock", mirror_path), MIRROR_LOCK_WAIT, MIRROR_LOCK_STALE) {
+            Ok(lock) => {
+                lock
+            },
+            Err(e) => {
+                return Err(git2::Error::from_str(&e.to_string()))
+            }
+        };
+
+        let mirror = match Repository::open_bare(&mirror_path) {
+            Ok(repo) => {
+                repo
+            },
+            Err(_) => {
+                info!(self.logger, "git_mirror_init"; "repo" => &self.repo, "path" => &mirror_path);
+
+                // e.g. a partial mirror from an interrupted first fetch
+                let _ = fs::remove_dir_all(&mirror_path);
+
+                let repo = Repository::init_bare(&mirror_path)?;
+                repo.remote_with_fetch("origin", &self.repo, FETCH_REFSPECS[0])?;
+                repo.remote_add_fetch("origin", FETCH_REFSPECS[1])?;
+
+                repo
+            }
+        };
+
+        // the repo url for a path is fixed, but keep the remote in sync if it was edited
+        mirror.remote_set_url("origin", &self.repo)?;
+
+        let mut remote = mirror.find_remote("origin")?;
+
+        let default_branch = self._git_default_branch(&mut remote);
+
+        // keep the lock fresh while objects are received, so a long fetch isn't taken for stale
+        let mut callbacks = self._git_callbacks(self.credential.clone());
+        let mut touched = Instant::now();
+        let lock_path = lock.path.clone();
+
+        callbacks.transfer_progress(move |_progress| {
+            if touched.elapsed() > MIRROR_LOCK_TOUCH {
+                FsLock::refresh(&lock_path);
+                touched = Instant::now();
+            }
+
+            true
+        });
 
         let mut fetch_options = git2::FetchOptions::new();
         fetch_options.remote_callbacks(callbacks);
+        fetch_options.prune(git2::FetchPrune::On);
+
+        remote.fetch(&FETCH_REFSPECS, Some(&mut fetch_options), None)?;
+
+        // point the mirror HEAD at the remote default branch, so workspaces get it as a local branch
+        if let Some(name) = default_branch {
+            mirror.set_head(&name)?;
+        }
 
-        let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(fetch_options);
+        info!(self.logger, "git_mirror_fetch_ok"; "repo" => &self.repo);
 
-        builder.clone(
-            &self.repo,
-            Path::new(&FsRoot::call(&self.id)),
-        )?;
+        let workspace = git2::build::RepoBuilder::new()
+            .clone_local(git2::build::CloneLocal::Local)
+            .with_checkout(self._git_checkout_none())
+            .clone(&mirror_path, Path::new(&FsRoot::call(&self.id)))?;
+
+        // every mirror branch as a remote branch, for deploys and protected branches other than the default
+        workspace.find_remote("origin")?.fetch(&[MIRROR_BRANCHES_REFSPEC], None, None)?;
+
+        // the workspace origin is the real repo, not the mirror
+        workspace.remote_set_url("origin", &self.repo)?;
 
         Ok(())
     }
 
+    // e.g. /var/tmp/deploybot/git/<sha256 of the normalized repo url>.git, one mirror per repo
+    fn _git_mirror_path(&self) -> String {
+        let repo = GitRepoNormalize::call(&self.repo).unwrap_or_else(|| self.repo.to_string());
+
+        let hash: String = openssl::sha::sha256(repo.as_bytes()).iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        format!("{}/{}.git", self.config.git.mirror_dir, &hash[..16])
+    }
+
     fn _git_revparse(&self) ->  Result<String, git2::Error> {
         let repo = Repository::open(&FsRoot::call(&self.id))?;
 
-        let revspec = repo.revparse(&self.tag)?;
+        // a workspace cloned from the mirror has branches other than the default as remote branches
+        let revspec = match repo.revparse(&self.tag) {
+            Ok(revspec) => {
+                revspec
+            },
+            Err(e) => {
+                repo.revparse(&format!("origin/{}", self.tag)).map_err(|_| e)?
+            }
+        };
 
         if revspec.mode().contains(git2::RevparseMode::SINGLE) {
             // println!("single {}", revspec.from().unwrap().id());
 
-            return Ok(revspec.from().unwrap().id().to_string())
+            // annotated tags resolve to the tag object, deploy the commit it points to
+            return Ok(revspec.from().unwrap().peel_to_commit()?.id().to_string())
         } else if revspec.mode().contains(git2::RevparseMode::RANGE) {
-            let to = revspec.to().unwrap();
-            let from = revspec.from().unwrap();
-            // println!("range {}", to.id());
+            // e.g. v1..v2, which end to deploy is ambiguous
+            return Err(git2::Error::from_str(&format!("revspec {} is a range, deploy a single commit", self.tag)));
+        } else {
+            return Err(git2::Error::from_str("invalid results from revparse"));
+        }
+    }
+}
+
+impl GitMirrorPrune {
+    pub fn new(config: &GitConfig, logger: slog::Logger) -> GitMirrorPrune {
+        GitMirrorPrune {
+            config: config.clone(),
+            logger: logger,
+        }
+    }
+
+    //
+    // remove mirrors not fetched in mirror_keep_days, e.g. of repos that are no longer deployed,
+    // a fetch writes the mirror's FETCH_HEAD, a mirror being fetched is locked and skipped
+    //
+
+    pub fn call(&self) -> Option<i32> {
+        if !self.config.mirror || self.config.mirror_keep_days == 0 {
+            return Some(0)
+        }
 
-            if revspec.mode().contains(git2::RevparseMode::MERGE_BASE) {
-                let _base = repo.merge_base(from.id(), to.id())?;
-                // println!("merge {}", base);
+        let entries = match fs::read_dir(&self.config.mirror_dir) {
+            Err(_) => {
+                return Some(0)
+            },
+            Ok(entries) => {
+                entries
             }
+        };
 
-            // println!("^{}", from.id());
+        let keep = Duration::from_secs(self.config.mirror_keep_days * MIRROR_KEEP_DAY);
 
-            return Ok(from.id().to_string())
-        } else {
-            return Err(git2::Error::from_str("invalid results from revparse"));
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path().to_string_lossy().to_string();
+
+            if !path.ends_with(".git") || !entry.path().is_dir() {
+                continue
+            }
+
+            let fetched = fs::metadata(format!("{}/FETCH_HEAD", path))
+                .or_else(|_| fs::metadata(&path))
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::now());
+
+            if fetched.elapsed().map(|elapsed| elapsed < keep).unwrap_or(true) {
+                continue
+            }
+
+            // don't wait for a deploy that is fetching it
+            let _lock = match FsLock::call(&format!("{}.lock", path), Duration::from_secs(0), MIRROR_LOCK_STALE) {
+                Ok(lock) => {
+                    lock
+                },
+                Err(_) => {
+                    continue
+                }
+            };
+
+            match fs::remove_dir_all(&path) {
+                Ok(_) => {
+                    info!(self.logger, "git_mirror_prune"; "path" => &path);
+                },
+                Err(e) => {
+                    warn!(self.logger, "git_mirror_prune_exception: {}", e; "path" => &path);
+                }
+            };
         }
+
+        Some(0)
     }
 }
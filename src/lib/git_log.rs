@@ -0,0 +1,94 @@
+use super::deploy_record::DeployRecordCommit;
+use super::fs::FsRoot;
+
+use git2::{Commit, Oid, Repository, Sort};
+
+// changelogs longer than this are truncated, e.g. the first deploy of a resource in months
+const CHANGELOG_MAX: usize = 100;
+
+//
+// metadata of the deployed commit and the commits since the previous deploy of the resource,
+// or for a rollback the commits that are rolled back, a previous sha missing from a shallow
+// checkout is an error
+//
+
+#[derive(Debug)]
+pub struct GitLog {
+    pub id: String,
+    pub sha: String,
+    pub previous_sha: String,
+}
+
+impl GitLog {
+    pub fn new(id: &String, sha: &String, previous_sha: &String) -> GitLog {
+        GitLog {
+            id: id.to_owned(),
+            sha: sha.to_owned(),
+            previous_sha: previous_sha.to_owned(),
+        }
+    }
+
+    // deployed commit and its full message
+    pub fn commit(&self) -> Result<(DeployRecordCommit, String), git2::Error> {
+        let repo = Repository::open(&FsRoot::call(&self.id))?;
+        let commit = repo.find_commit(Oid::from_str(&self.sha)?)?;
+
+        let message = commit.message().unwrap_or("").trim().to_string();
+
+        Ok((GitLog::record_commit(&commit), message))
+    }
+
+    //
+    // commits reachable from sha but not from previous_sha, newest first, whether it was truncated
+    // and whether it's a rollback, then the commits are those reachable from previous_sha only
+    //
+
+    pub fn call(&self) -> Result<(Vec<DeployRecordCommit>, bool, bool), git2::Error> {
+        if self.previous_sha.is_empty() || self.previous_sha == self.sha {
+            return Ok((Vec::new(), false, false))
+        }
+
+        let repo = Repository::open(&FsRoot::call(&self.id))?;
+        let previous = Oid::from_str(&self.previous_sha)?;
+
+        // e.g. history older than git_depth
+        if repo.find_commit(previous).is_err() {
+            return Err(git2::Error::from_str(&format!("previous commit {} is not in the checkout", self.previous_sha)))
+        }
+
+        let sha = Oid::from_str(&self.sha)?;
+
+        // the deployed commit is an ancestor of the previous one
+        let rollback = repo.graph_descendant_of(previous, sha)?;
+
+        let (from, to) = if rollback { (previous, sha) } else { (sha, previous) };
+
+        let mut revwalk = repo.revwalk()?;
+        revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        revwalk.push(from)?;
+        revwalk.hide(to)?;
+
+        let mut commits = Vec::new();
+
+        for oid in revwalk {
+            if commits.len() == CHANGELOG_MAX {
+                return Ok((commits, true, rollback))
+            }
+
+            commits.push(GitLog::record_commit(&repo.find_commit(oid?)?));
+        }
+
+        Ok((commits, false, rollback))
+    }
+
+    pub fn record_commit(commit: &Commit) -> DeployRecordCommit {
+        let author = commit.author();
+
+        DeployRecordCommit {
+            sha: commit.id().to_string(),
+            author: format!("{} <{}>", author.name().unwrap_or(""), author.email().unwrap_or("")),
+            summary: commit.summary().unwrap_or("").to_string(),
+            time: commit.time().seconds(),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use super::config::GitRefPolicyConfig;
+use super::fs::FsRoot;
+
+use git2::{Oid, Repository};
+use std::io::{Error, ErrorKind, Result};
+
+//
+// check the deploy tag against the resource's ref policy from the deploybot config, e.g. prod only deploys tags matching
+// "v*.*.*" whose commit is on main, revspecs like main~2 or a..b are never allowed by a policy
+//
+
+#[derive(Debug)]
+pub struct GitRefPolicy {
+    pub id: String,
+    pub tag: String,
+    pub sha: String,
+    pub policy: GitRefPolicyConfig,
+}
+
+impl GitRefPolicy {
+    pub fn new(id: &String, tag: &String, sha: &String, policy: &GitRefPolicyConfig) -> GitRefPolicy {
+        GitRefPolicy {
+            id: id.to_owned(),
+            tag: tag.to_owned(),
+            sha: sha.to_owned(),
+            policy: policy.clone(),
+        }
+    }
+
+    // returns a description of the allowed ref, e.g. "tag v1.2.0 on main"
+    pub fn call(&self) -> Result<String> {
+        let repo = match Repository::open(&FsRoot::call(&self.id)) {
+            Err(e) => {
+                return Err(Error::new(ErrorKind::Other, e.to_string()))
+            },
+            Ok(repo) => {
+                repo
+            }
+        };
+
+        let (kind, allowed) = if self._ref_exists(&repo, &format!("refs/tags/{}", self.tag)) {
+            ("tag", self.policy.tags.iter().any(|pattern| GitRefPolicy::glob_match(pattern, &self.tag)))
+        } else if self._branch_oid(&repo, &self.tag).is_some() {
+            ("branch", self.policy.branches.iter().any(|pattern| GitRefPolicy::glob_match(pattern, &self.tag)))
+        } else if self._is_sha() {
+            ("commit", self.policy.commits)
+        } else {
+            ("revspec", false)
+        };
+
+        if !allowed {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("{} {} is not allowed by the refs policy of the resource", kind, self.tag)))
+        }
+
+        if self.policy.protected_branches.is_empty() {
+            return Ok(format!("{} {}", kind, self.tag))
+        }
+
+        let commit = match Oid::from_str(&self.sha) {
+            Err(e) => {
+                return Err(Error::new(ErrorKind::InvalidInput, e.to_string()))
+            },
+            Ok(oid) => {
+                oid
+            }
+        };
+
+        for branch in self.policy.protected_branches.iter() {
+            let tip = match self._branch_oid(&repo, branch) {
+                Some(tip) => tip,
+                None => continue,
+            };
+
+            if tip == commit || repo.graph_descendant_of(tip, commit).unwrap_or(false) {
+                return Ok(format!("{} {} on {}", kind, self.tag, branch))
+            }
+        }
+
+        Err(Error::new(ErrorKind::PermissionDenied, format!("commit {} is not on a protected branch ({})", self.sha, self.policy.protected_branches.join(", "))))
+    }
+
+    //
+    // glob match a ref name, * matches any run of characters and ? a single one, e.g.
+    // "v*.*.*" matches v1.2.0 and "release/*" matches release/2024-01
+    //
+
+    pub fn glob_match(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+
+        let (mut p, mut n) = (0, 0);
+        let mut star: Option<(usize, usize)> = None;
+
+        while n < name.len() {
+            if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+                p += 1;
+                n += 1;
+            } else if p < pattern.len() && pattern[p] == '*' {
+                star = Some((p, n));
+                p += 1;
+            } else if let Some((star_p, star_n)) = star {
+                // backtrack, the last * takes one more character
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            } else {
+                return false
+            }
+        }
+
+        pattern[p..].iter().all(|c| *c == '*')
+    }
+
+    // a workspace has local branches, a clone from the mirror has the default one locally and the rest as remote branches
+    fn _branch_oid(&self, repo: &Repository, branch: &str) -> Option<Oid> {
+        ["refs/heads", "refs/remotes/origin"].iter()
+            .filter_map(|prefix| repo.refname_to_id(&format!("{}/{}", prefix, branch)).ok())
+            .next()
+    }
+
+    fn _ref_exists(&self, repo: &Repository, name: &str) -> bool {
+        repo.find_reference(name).is_ok()
+    }
+
+    // full or abbreviated sha, not a revspec like main~2
+    fn _is_sha(&self) -> bool {
+        self.tag.len() >= 7
+            && self.tag.chars().all(|c| c.is_ascii_hexdigit())
+            && self.sha.starts_with(&self.tag.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use git2::Signature;
+    use std::fs;
+    use ulid::Ulid;
+
+    // main with two commits, tagged v1.2.0 on the first, and a feature branch off main
+    fn repo_init() -> (String, Oid, Oid, Oid) {
+        let id = format!("deploybot-test-refs-{}", Ulid::new());
+        let path = FsRoot::call(&id);
+
+        fs::create_dir_all(&path).unwrap();
+
+        let repo = Repository::init(&path).unwrap();
+        let signature = Signature::now("deploybot", "deploybot@example.com").unwrap();
+        let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+
+        let first = repo.commit(Some("refs/heads/main"), &signature, &signature, "first", &tree, &[]).unwrap();
+        let first_commit = repo.find_commit(first).unwrap();
+        let second = repo.commit(Some("refs/heads/main"), &signature, &signature, "second", &tree, &[&first_commit]).unwrap();
+        let feature = repo.commit(Some("refs/heads/feature"), &signature, &signature, "feature", &tree, &[&first_commit]).unwrap();
+
+        repo.tag_lightweight("v1.2.0", first_commit.as_object(), false).unwrap();
+
+        (id, first, second, feature)
+    }
+
+    fn policy_call(id: &str, tag: &str, sha: Oid, policy: &GitRefPolicyConfig) -> Result<String> {
+        GitRefPolicy::new(&id.to_string(), &tag.to_string(), &sha.to_string(), policy).call()
+    }
+
+    #[test]
+    fn glob_match() {
+        assert!(GitRefPolicy::glob_match("v*.*.*", "v1.2.0"));
+        assert!(GitRefPolicy::glob_match("v*.*.*", "v10.20.30-rc1"));
+        assert!(!GitRefPolicy::glob_match("v*.*.*", "v1.2"));
+        assert!(GitRefPolicy::glob_match("release/*", "release/2024-01"));
+        assert!(!GitRefPolicy::glob_match("release/*", "releases/2024-01"));
+        assert!(GitRefPolicy::glob_match("v1.?", "v1.2"));
+        assert!(!GitRefPolicy::glob_match("v1.?", "v1.20"));
+        assert!(GitRefPolicy::glob_match("main", "main"));
+        assert!(!GitRefPolicy::glob_match("main", "main2"));
+        assert!(GitRefPolicy::glob_match("*", ""));
+        assert!(GitRefPolicy::glob_match("**", "a"));
+        assert!(!GitRefPolicy::glob_match("", "a"));
+    }
+
+    #[test]
+    fn call_classifies_refs() {
+        let (id, first, second, _) = repo_init();
+
+        let policy = GitRefPolicyConfig {
+            tags: vec!["v*.*.*".to_string()],
+            branches: vec!["main".to_string()],
+            commits: true,
+            ..GitRefPolicyConfig::default()
+        };
+
+        assert_eq!(policy_call(&id, "v1.2.0", first, &policy).unwrap(), "tag v1.2.0");
+        assert_eq!(policy_call(&id, "main", second, &policy).unwrap(), "branch main");
+        assert_eq!(policy_call(&id, &second.to_string()[..7], second, &policy).unwrap(), format!("commit {}", &second.to_string()[..7]));
+
+        // revspecs are rejected even when commits are allowed
+        let e = policy_call(&id, "main~1", first, &policy).unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(e.to_string().starts_with("revspec main~1"));
+
+        fs::remove_dir_all(FsRoot::call(&id)).unwrap();
+    }
+
+    #[test]
+    fn call_rejects_unmatched_refs() {
+        let (id, first, second, feature) = repo_init();
+
+        let policy = GitRefPolicyConfig {
+            tags: vec!["v2.*".to_string()],
+            branches: vec!["release/*".to_string()],
+            ..GitRefPolicyConfig::default()
+        };
+
+        assert!(policy_call(&id, "v1.2.0", first, &policy).unwrap_err().to_string().starts_with("tag v1.2.0"));
+        assert!(policy_call(&id, "main", second, &policy).unwrap_err().to_string().starts_with("branch main"));
+        assert!(policy_call(&id, "feature", feature, &policy).unwrap_err().to_string().starts_with("branch feature"));
+        assert!(policy_call(&id, &second.to_string(), second, &policy).unwrap_err().to_string().starts_with("commit"));
+
+        fs::remove_dir_all(FsRoot::call(&id)).unwrap();
+    }
+
+    #[test]
+    fn call_protected_branches() {
+        let (id, first, _, feature) = repo_init();
+
+        let policy = GitRefPolicyConfig {
+            branches: vec!["*".to_string()],
+            commits: true,
+            protected_branches: vec!["main".to_string()],
+            ..GitRefPolicyConfig::default()
+        };
+
+        assert_eq!(policy_call(&id, "v1.2.0", first, &GitRefPolicyConfig { tags: vec!["v*".to_string()], ..policy.clone() }).unwrap(), "tag v1.2.0 on main");
+        assert_eq!(policy_call(&id, &first.to_string(), first, &policy).unwrap(), format!("commit {} on main", first));
+
+        let e = policy_call(&id, "feature", feature, &policy).unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(e.to_string().contains("is not on a protected branch (main)"));
+
+        fs::remove_dir_all(FsRoot::call(&id)).unwrap();
+    }
+}
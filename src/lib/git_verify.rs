@@ -0,0 +1,107 @@
+use super::config::GitConfig;
+use super::fs::FsRoot;
+
+use git2::{ObjectType, Oid, Repository};
+use std::io::{Error, ErrorKind, Result};
+use std::process::Command;
+
+const SIGNATURE_MARKERS: [&str; 2] = ["-----BEGIN PGP SIGNATURE-----", "-----BEGIN SSH SIGNATURE-----"];
+
+//
+// verify the signature of the deployed annotated tag, or of the commit when the deploy is not
+// from an annotated tag, against the allowed ssh signers file or the trusted gpg keyring,
+// the git cli does the verification
+//
+
+#[derive(Debug)]
+pub struct GitVerify {
+    pub id: String,
+    pub tag: String,
+    pub sha: String,
+    pub config: GitConfig,
+}
+
+impl GitVerify {
+    pub fn new(id: &String, tag: &String, sha: &String, config: &GitConfig) -> GitVerify {
+        GitVerify {
+            id: id.to_owned(),
+            tag: tag.to_owned(),
+            sha: sha.to_owned(),
+            config: config.clone(),
+        }
+    }
+
+    // returns a description of what was verified, e.g. "tag v1.2.0"
+    pub fn call(&self) -> Result<String> {
+        if self.config.allowed_signers.is_empty() && self.config.gpg_home.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "signed tags are required but no allowed_signers or gpg_home is configured"))
+        }
+
+        let repo = match Repository::open(&FsRoot::call(&self.id)) {
+            Err(e) => {
+                return Err(Error::new(ErrorKind::Other, e.to_string()))
+            },
+            Ok(repo) => {
+                repo
+            }
+        };
+
+        // annotated tag object, if the deploy tag names one
+        let tag_oid = repo.revparse_single(&format!("refs/tags/{}", self.tag)).ok()
+            .filter(|object| object.kind() == Some(ObjectType::Tag))
+            .map(|object| object.id());
+
+        let (kind, name, oid, signed) = match tag_oid {
+            Some(oid) => {
+                let message = repo.find_tag(oid).ok().and_then(|tag| tag.message().map(|s| s.to_string())).unwrap_or_default();
+
+                ("tag", self.tag.to_string(), oid, SIGNATURE_MARKERS.iter().any(|marker| message.contains(marker)))
+            },
+            None => {
+                let oid = match Oid::from_str(&self.sha) {
+                    Err(e) => {
+                        return Err(Error::new(ErrorKind::InvalidInput, e.to_string()))
+                    },
+                    Ok(oid) => {
+                        oid
+                    }
+                };
+
+                ("commit", self.sha.to_string(), oid, repo.extract_signature(&oid, None).is_ok())
+            }
+        };
+
+        if !signed {
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("{} {} is not signed", kind, name)))
+        }
+
+        let output = self._command(&[&format!("verify-{}", kind), &oid.to_string()]).output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = stderr.lines().filter(|line| !line.trim().is_empty()).next_back().unwrap_or("").trim().to_string();
+
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("{} {} is not signed by an allowed key: {}", kind, name, reason)))
+        }
+
+        Ok(format!("{} {}", kind, name))
+    }
+
+    fn _command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new("git");
+
+        command.args(args).current_dir(FsRoot::call(&self.id));
+
+        if !self.config.allowed_signers.is_empty() {
+            command.env("GIT_CONFIG_COUNT", "1")
+                .env("GIT_CONFIG_KEY_0", "gpg.ssh.allowedSignersFile")
+                .env("GIT_CONFIG_VALUE_0", &self.config.allowed_signers);
+        }
+
+        if !self.config.gpg_home.is_empty() {
+            command.env("GNUPGHOME", &self.config.gpg_home);
+        }
+
+        command
+    }
+}
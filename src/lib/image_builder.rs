@@ -0,0 +1,523 @@
+use super::config::{BuildBackend, Config};
+use super::fs::{FsDockerConfig, FsRoot};
+use super::kube_image_set::KubeImageSet;
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+// registry answers for a missing tag or repository, any other inspect failure is an error
+const IMAGE_MISSING_ERRORS: [&str; 4] = [
+    "manifest unknown",
+    "name unknown",
+    "no such manifest",
+    "not found",
+];
+
+//
+// build, push and inspect images with the docker cli, or without a docker daemon using
+// buildah or a kaniko executor pod, daemonless backends query the registry with skopeo
+//
+
+#[derive(Debug)]
+pub struct ImageBuilder {
+    pub backend: BuildBackend,
+    pub id: String,
+    pub config: Config,
+}
+
+// backend independent build options, values are already rendered
+#[derive(Clone, Debug, Default)]
+pub struct ImageBuildSpec {
+    pub docker_file: String,
+    pub image_tag: String,
+    pub context: String,
+    pub args: Vec<(String, String)>,
+    pub labels: Vec<(String, String)>,
+    pub target: Option<String>,
+    pub platform: Option<String>,
+    pub secrets: Vec<(String, String)>,  // secret id, file
+    pub cache_from: Vec<String>,  // images to use as cache, e.g. the last deployed image
+    pub cache_ref: Option<String>,  // registry build cache, imported and exported
+}
+
+// build steps and how many were cached, read from the builder output
+#[derive(Clone, Debug, Default)]
+pub struct ImageBuildStats {
+    pub steps: u32,
+    pub cached: u32,
+}
+
+impl ImageBuilder {
+    pub fn new(backend: &BuildBackend, id: &String, config: &Config) -> ImageBuilder {
+        ImageBuilder {
+            backend: backend.clone(),
+            id: id.to_owned(),
+            config: config.clone(),
+        }
+    }
+
+    //
+    // whether the registry has the image, an error when the registry couldn't be asked, e.g. a
+    // missing cli or expired credentials, so it isn't mistaken for a missing image
+    //
+
+    pub fn exists(&self, image_tag: &String) -> Result<bool> {
+        let output = match self.backend {
+            BuildBackend::Docker => {
+                self._docker_command(&["manifest", "inspect", image_tag]).output()?
+            },
+            _ => {
+                self._command("skopeo", &["inspect", "--raw", &format!("docker://{}", image_tag)]).output()?
+            }
+        };
+
+        if output.status.success() {
+            return Ok(true)
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+
+        // docker: no such manifest, skopeo: manifest unknown
+        if IMAGE_MISSING_ERRORS.iter().any(|error| stderr.to_lowercase().contains(error)) {
+            return Ok(false)
+        }
+
+        Err(Error::new(ErrorKind::Other, format!("image inspect {}: {}", output.status, stderr)))
+    }
+
+    pub fn build(&self, spec: &ImageBuildSpec) -> Result<(ExitStatus, ImageBuildStats)> {
+        match self.backend {
+            BuildBackend::Docker => {
+                // the default docker driver can't export registry caches
+                if spec.cache_ref.is_some() && self.config.docker.buildx_builder.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "cache_ref requires docker.buildx_builder"))
+                }
+
+                let mut command = self._docker_command(&["build", "--progress=plain"]);
+
+                command.args(self._build_args(spec, self._docker_cache_args(spec))).env("DOCKER_BUILDKIT", "1");
+
+                self._build_scan(command)
+            },
+            BuildBackend::Buildah => {
+                let mut command = self._command("buildah", &["build", "--layers"]);
+
+                command.args(self._build_args(spec, self._buildah_cache_args(spec)));
+
+                self._build_scan(command)
+            },
+            BuildBackend::Kaniko => {
+                if !spec.secrets.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "kaniko does not support build secrets"))
+                }
+
+                self._kaniko_build(spec)
+            }
+        }
+    }
+
+    // kaniko pushes to its destination as part of the build
+    pub fn pushes_on_build(&self) -> bool {
+        self.backend == BuildBackend::Kaniko
+    }
+
+    pub fn push(&self, image_tag: &String) -> Result<ExitStatus> {
+        match self.backend {
+            BuildBackend::Docker => {
+                Ok(self._docker_command(&["push", image_tag]).status()?)
+            },
+            BuildBackend::Buildah => {
+                Ok(self._command("buildah", &["push", image_tag]).status()?)
+            },
+            BuildBackend::Kaniko => {
+                Err(Error::new(ErrorKind::Unsupported, "kaniko pushes during the build"))
+            }
+        }
+    }
+
+    // registry digest of a pushed image, e.g. sha256:..., read from the registry so a reused
+    // image doesn't need a local copy
+    pub fn digest(&self, image_tag: &String) -> Result<String> {
+        let output = match self.backend {
+            BuildBackend::Docker => {
+                self._docker_command(&["buildx", "imagetools", "inspect", "--format", "{{.Manifest.Digest}}", image_tag]).output()?
+            },
+            _ => {
+                self._command("skopeo", &["inspect", "--format", "{{.Digest}}", &format!("docker://{}", image_tag)]).output()?
+            }
+        };
+
+        if !output.status.success() {
+            return Err(Error::new(ErrorKind::Other, format!("image inspect {}: {}", output.status, String::from_utf8_lossy(&output.stderr).trim())))
+        }
+
+        let digest = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        if !digest.starts_with("sha256:") {
+            return Err(Error::new(ErrorKind::NotFound, format!("no digest for {}", image_tag)))
+        }
+
+        Ok(digest)
+    }
+
+    //
+    // docker and buildah build args, e.g.
+    // -f Dockerfile -t image:tag --build-arg GIT_SHA=... --label ... --secret id=npm,src=... .
+    //
+
+    fn _build_args(&self, spec: &ImageBuildSpec, cache_args: Vec<String>) -> Vec<String> {
+        let mut args = vec![
+            "-f".to_string(),
+            spec.docker_file.to_string(),
+            "-t".to_string(),
+            spec.image_tag.to_string(),
+        ];
+
+        for (key, value) in spec.args.iter() {
+            args.push("--build-arg".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+
+        if let Some(target) = &spec.target {
+            args.push("--target".to_string());
+            args.push(target.to_string());
+        }
+
+        if let Some(platform) = &spec.platform {
+            args.push("--platform".to_string());
+            args.push(platform.to_string());
+        }
+
+        for (key, value) in spec.labels.iter() {
+            args.push("--label".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+
+        for (id, file) in spec.secrets.iter() {
+            args.push("--secret".to_string());
+            args.push(format!("id={},src={}", id, file));
+        }
+
+        args.extend(cache_args);
+
+        args.push(spec.context.to_string());
+
+        args
+    }
+
+    //
+    // run the executor as a pod so builds don't run in deploybot's container, the context is
+    // streamed as a tar on stdin and registry credentials are mounted from a secret
+    //
+
+    fn _kaniko_build(&self, spec: &ImageBuildSpec) -> Result<(ExitStatus, ImageBuildStats)> {
+        let tag_hash: String = openssl::sha::sha256(spec.image_tag.as_bytes()).iter().take(4)
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        // pod and secret name, e.g. deploybot-kaniko-01h...-1a2b3c4d
+        let name = format!("deploybot-kaniko-{}-{}", self.id.to_lowercase(), tag_hash);
+
+        let auth_file = format!("{}/config.json", FsDockerConfig::call(&self.id));
+        let auth_secret = Path::new(&auth_file).is_file();
+
+        if auth_secret {
+            let status = self._kubectl_command(&["create", "secret", "generic", &name, &format!("--from-file=config.json={}", auth_file)]).status()?;
+
+            if !status.success() {
+                return Err(Error::new(ErrorKind::Other, format!("kubectl create secret {}", status)))
+            }
+        }
+
+        let result = self._kaniko_pod(&name, spec, auth_secret);
+
+        if auth_secret {
+            let _ = self._kubectl_command(&["delete", "secret", &name, "--ignore-not-found"]).status();
+        }
+
+        result
+    }
+
+    fn _kaniko_pod(&self, name: &str, spec: &ImageBuildSpec, auth_secret: bool) -> Result<(ExitStatus, ImageBuildStats)> {
+        let mut container = serde_json::json!({
+            "name": "kaniko",
+            "image": self.config.docker.kaniko_image,
+            "args": self._kaniko_args(spec),
+            "stdin": true,
+            "stdinOnce": true,
+        });
+
+        let mut pod_spec = serde_json::json!({
+            "restartPolicy": "Never",
+        });
+
+        if auth_secret {
+            container["volumeMounts"] = serde_json::json!([{ "name": "docker-config", "mountPath": "/kaniko/.docker" }]);
+            pod_spec["volumes"] = serde_json::json!([{ "name": "docker-config", "secret": { "secretName": name } }]);
+        }
+
+        pod_spec["containers"] = serde_json::json!([container]);
+
+        let overrides = serde_json::json!({ "apiVersion": "v1", "spec": pod_spec }).to_string();
+
+        // the checkout without git metadata
+        let mut tar = Command::new("tar")
+            .args(&["-cz", "--exclude=.git", "-C", &FsRoot::call(&self.id), "."])
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let tar_stdout = match tar.stdout.take() {
+            Some(stdout) => {
+                stdout
+            },
+            None => {
+                return Err(Error::new(ErrorKind::Other, "tar has no stdout"))
+            }
+        };
+
+        let mut command = self._kubectl_command(&[
+            "run", name, "--rm", "--stdin", "--quiet", "--restart=Never",
+            &format!("--image={}", self.config.docker.kaniko_image),
+            &format!("--overrides={}", overrides),
+        ]);
+
+        command.stdin(Stdio::from(tar_stdout));
+
+        let result = self._build_scan(command);
+
+        let tar_status = tar.wait()?;
+
+        if !tar_status.success() {
+            return Err(Error::new(ErrorKind::Other, format!("tar {}", tar_status)))
+        }
+
+        result
+    }
+
+    fn _kubectl_command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new("kubectl");
+
+        if !self.config.docker.kaniko_kube_context.is_empty() {
+            command.arg(format!("--context={}", self.config.docker.kaniko_kube_context));
+        }
+
+        command.arg(format!("--namespace={}", self.config.docker.kaniko_namespace)).args(args);
+
+        command
+    }
+
+    // kaniko executor args, the context is extracted to /kaniko/buildcontext from stdin
+    fn _kaniko_args(&self, spec: &ImageBuildSpec) -> Vec<String> {
+        let mut args = vec![
+            format!("--dockerfile=/kaniko/buildcontext/{}", spec.docker_file),
+            "--context=tar://stdin".to_string(),
+            format!("--destination={}", spec.image_tag),
+        ];
+
+        if spec.context != "." {
+            args.push(format!("--context-sub-path={}", spec.context));
+        }
+
+        for (key, value) in spec.args.iter() {
+            args.push(format!("--build-arg={}={}", key, value));
+        }
+
+        if let Some(target) = &spec.target {
+            args.push(format!("--target={}", target));
+        }
+
+        if let Some(platform) = &spec.platform {
+            args.push(format!("--custom-platform={}", platform));
+        }
+
+        for (key, value) in spec.labels.iter() {
+            args.push(format!("--label={}={}", key, value));
+        }
+
+        // kaniko caches layers in a repository, without a tag
+        if let Some(cache_ref) = &spec.cache_ref {
+            args.push("--cache=true".to_string());
+            args.push(format!("--cache-repo={}", KubeImageSet::repository(cache_ref)));
+        }
+
+        args
+    }
+
+    //
+    // images embed inline cache metadata so the next deploy can use them with cache_from, the
+    // registry cache runs on a buildx builder that can export caches, e.g. docker-container,
+    // which keeps images in its own store until they are loaded for the push
+    //
+
+    fn _docker_cache_args(&self, spec: &ImageBuildSpec) -> Vec<String> {
+        let mut args = vec![
+            "--build-arg".to_string(),
+            "BUILDKIT_INLINE_CACHE=1".to_string(),
+        ];
+
+        for image in spec.cache_from.iter() {
+            args.push("--cache-from".to_string());
+            args.push(image.to_string());
+        }
+
+        if let Some(cache_ref) = &spec.cache_ref {
+            args.push("--builder".to_string());
+            args.push(self.config.docker.buildx_builder.to_string());
+            args.push("--load".to_string());
+            args.push("--cache-from".to_string());
+            args.push(format!("type=registry,ref={}", cache_ref));
+            args.push("--cache-to".to_string());
+            args.push(format!("type=registry,ref={},mode=max", cache_ref));
+        }
+
+        args
+    }
+
+    // buildah caches layers in a repository, without a tag
+    fn _buildah_cache_args(&self, spec: &ImageBuildSpec) -> Vec<String> {
+        match &spec.cache_ref {
+            Some(cache_ref) => {
+                let repository = KubeImageSet::repository(cache_ref);
+
+                vec![
+                    "--cache-from".to_string(),
+                    repository.to_string(),
+                    "--cache-to".to_string(),
+                    repository.to_string(),
+                ]
+            },
+            None => {
+                Vec::new()
+            }
+        }
+    }
+
+    //
+    // run a build, passing its output through while counting steps and cache hits:
+    // buildkit '#5 [2/4] RUN ...' and '#5 CACHED', buildah 'STEP 2/4:' and '--> Using cache',
+    // kaniko 'Using caching version of cmd' and 'No cached layer found for cmd'
+    //
+
+    fn _build_scan(&self, mut command: Command) -> Result<(ExitStatus, ImageBuildStats)> {
+        let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+
+        let stdout_thread = thread::spawn(move || {
+            match stdout {
+                Some(stdout) => ImageBuilder::_output_scan(stdout, std::io::stdout()),
+                None => ImageBuildStats::default(),
+            }
+        });
+
+        let mut stats = match stderr {
+            Some(stderr) => ImageBuilder::_output_scan(stderr, std::io::stderr()),
+            None => ImageBuildStats::default(),
+        };
+
+        let stdout_stats = stdout_thread.join().unwrap_or_default();
+
+        stats.steps += stdout_stats.steps;
+        stats.cached += stdout_stats.cached;
+
+        let status = child.wait()?;
+
+        Ok((status, stats))
+    }
+
+    fn _output_scan<R: Read, W: Write>(input: R, mut output: W) -> ImageBuildStats {
+        let mut stats = ImageBuildStats::default();
+
+        // buildkit repeats a step's header when its output interleaves with other steps
+        let mut buildkit_steps = HashSet::new();
+
+        for line in BufReader::new(input).lines() {
+            let line = match line {
+                Err(_) => {
+                    break
+                },
+                Ok(line) => {
+                    line
+                }
+            };
+
+            let _ = writeln!(output, "{}", line);
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            match words.as_slice() {
+                // e.g. #5 [builder 2/4] RUN, '[internal]' steps and repeated headers are not counted
+                [step, ..] if step.starts_with('#') && ImageBuilder::_buildkit_stage(&line) && buildkit_steps.insert(step.to_string()) => {
+                    stats.steps += 1;
+                },
+                [step, "CACHED"] if step.starts_with('#') => {
+                    stats.cached += 1;
+                },
+                ["STEP", ..] => {
+                    stats.steps += 1;
+                },
+                ["-->", "Using", "cache", ..] => {
+                    stats.cached += 1;
+                },
+                _ if line.contains("Using caching version of cmd") => {
+                    stats.steps += 1;
+                    stats.cached += 1;
+                },
+                _ if line.contains("No cached layer found for cmd") => {
+                    stats.steps += 1;
+                },
+                _ => {}
+            };
+        }
+
+        stats
+    }
+
+    // dockerfile steps are numbered, e.g. '[2/4]' or '[builder 2/4]'
+    fn _buildkit_stage(line: &str) -> bool {
+        match (line.find('['), line.find(']')) {
+            (Some(start), Some(end)) if start < end => {
+                line[start..end].split_whitespace().last().map(|s| s.contains('/')) == Some(true)
+            },
+            _ => {
+                false
+            }
+        }
+    }
+
+    fn _docker_command(&self, args: &[&str]) -> Command {
+        let mut command = self._command("docker", &["--host", &self.config.docker.host_uri]);
+
+        command.args(args);
+
+        command
+    }
+
+    fn _command(&self, cmd: &str, args: &[&str]) -> Command {
+        let mut command = Command::new(cmd);
+
+        command.args(args).current_dir(FsRoot::call(&self.id));
+
+        // registry credentials written for this deploy, docker and kaniko read DOCKER_CONFIG,
+        // buildah and skopeo REGISTRY_AUTH_FILE
+        let docker_config = FsDockerConfig::call(&self.id);
+        let auth_file = format!("{}/config.json", docker_config);
+
+        if Path::new(&auth_file).is_file() {
+            command.env("DOCKER_CONFIG", &docker_config).env("REGISTRY_AUTH_FILE", &auth_file);
+
+            // buildx builders and their state stay in the host's docker config
+            if std::env::var_os("BUILDX_CONFIG").is_none() {
+                if let Some(ambient) = FsDockerConfig::ambient() {
+                    command.env("BUILDX_CONFIG", format!("{}/buildx", ambient));
+                }
+            }
+        }
+
+        command
+    }
+}
@@ -1,26 +1,26 @@
+use super::docker::DockerImage;
 use super::kube_files_apply::KubeFilesApply;
+use super::kube_files_render::KubeFilesRender;
 use super::kube_files_rewriter::KubeFilesRewriter;
-use super::kube_resource::KubeResourceResolve;
+use super::kube_resource::KubeResource;
+use super::template::TemplateVars;
 
 #[derive(Debug)]
 pub struct KubeStage {
     pub id: String,
-    pub resource_file: String,
-    pub resource_key: String,
-    pub image_tag: String,
+    pub resource: KubeResource,
+    pub vars: TemplateVars,
+    pub images: Vec<DockerImage>,
     pub logger: slog::Logger,
 }
 
 impl KubeStage {
-    pub fn new(id: &String, resource_path: &String, image_tag: &String, logger: slog::Logger) -> KubeStage {
-        let resource_vec: Vec<_> = resource_path.split(":").collect();
-        let resource_file = KubeResourceResolve::call(id, resource_path);
-
+    pub fn new(id: &String, resource: &KubeResource, vars: &TemplateVars, images: &[DockerImage], logger: slog::Logger) -> KubeStage {
         KubeStage {
             id: id.to_owned(),
-            resource_file: resource_file,
-            resource_key: resource_vec[1].to_owned(),
-            image_tag: image_tag.to_owned(),
+            resource: resource.clone(),
+            vars: vars.clone(),
+            images: images.to_vec(),
             logger: logger,
         }
     }
@@ -28,12 +28,12 @@ impl KubeStage {
     pub fn call(&self) -> Option<i32> {
         let files_rewriter = KubeFilesRewriter::new(
             &self.id,
-            &self.resource_file,
-            &self.resource_key,
-            &self.image_tag,
+            &self.resource,
+            &self.vars,
+            &self.images,
         );
 
-        let files_latest = match files_rewriter.call() {
+        let mut files_latest = match files_rewriter.call(&self.logger) {
             None => {
                 return Some(400)
             },
@@ -42,12 +42,29 @@ impl KubeStage {
             }
         };
 
+        // kustomize or helm manifests
+
+        let files_render = KubeFilesRender::new(
+            &self.id,
+            &self.resource,
+            &self.vars,
+            &self.images,
+        );
+
+        match files_render.call(&self.logger) {
+            None => {
+                return Some(400)
+            },
+            Some(files) => {
+                files_latest.extend(files);
+            }
+        };
+
         // kubectl apply new resources (e.g. deployments, sts)
 
         let files_apply = KubeFilesApply::new(
             &self.id,
-            &self.resource_file,
-            &self.resource_key,
+            &self.resource,
             files_latest,
         );
 
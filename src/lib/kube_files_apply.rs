@@ -1,5 +1,5 @@
 use super::fs::FsRoot;
-use super::kube_resource::KubeResourceParser;
+use super::kube_resource::KubeResource;
 
 use slog::{info, error};
 use std::io::Result;
@@ -8,51 +8,24 @@ use std::process::{Command, ExitStatus};
 #[derive(Debug)]
 pub struct KubeFilesApply {
     pub id: String,
-    pub resource_file: String,
-    pub resource_key: String,
+    pub resource: KubeResource,
     pub files: Vec<String>,
 }
 
 impl KubeFilesApply {
 
-    pub fn new(id: &String, resource_file: &String, resource_key: &String, files: Vec<String>) -> KubeFilesApply {
+    pub fn new(id: &String, resource: &KubeResource, files: Vec<String>) -> KubeFilesApply {
         KubeFilesApply {
             id: id.to_owned(),
-            resource_file: resource_file.to_owned(),
-            resource_key: resource_key.to_owned(),
+            resource: resource.clone(),
             files: files,
         }
     }
 
     pub fn call(&self, logger: slog::Logger) -> Option<i32> {
-        let resource_parser = KubeResourceParser::new(
-            &self.resource_file,
-            &self.resource_key,
-        );
-
-        let resource = match resource_parser.call() {
-            Some(value) => {
-                value
-            },
-            None => {
-                return None
-            }
-        };
-
-        // get list of all console and resource file names
-
-        let kube_context = match resource.get("kube_context") {
-            Some(value) => {
-                info!(logger, "kube_context_ok"; "value" => value.as_str().unwrap());
+        let kube_context = self.resource.kube_context.clone();
 
-                value.as_str().unwrap().to_owned()
-            },
-            None => {
-                error!(logger, "kube_context_exception");
-
-                return None
-            }
-        };
+        info!(logger, "kube_context_ok"; "value" => &kube_context);
 
         let mut apply_errors = 0;
         let mut apply_success = 0;
@@ -0,0 +1,234 @@
+use super::docker::DockerImage;
+use super::fs::FsRoot;
+use super::kube_resource::{KubeHelm, KubeKustomize, KubeResource};
+use super::template::{TemplateRender, TemplateVars};
+
+use slog::{error, info};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::process::Command;
+
+// scratch dir in the checkout for rendering, the checked out files are never modified
+const RENDER_DIR: &str = ".deploybot-render";
+
+//
+// render kustomize or helm manifests into a single file that is applied with the resource files
+//
+
+#[derive(Debug)]
+pub struct KubeFilesRender {
+    id: String,
+    vars: TemplateVars,
+    images: Vec<DockerImage>,
+    pub resource: KubeResource,
+}
+
+impl KubeFilesRender {
+    pub fn new(id: &String, resource: &KubeResource, vars: &TemplateVars, images: &[DockerImage]) -> KubeFilesRender {
+        KubeFilesRender {
+            id: id.to_owned(),
+            resource: resource.clone(),
+            vars: vars.clone(),
+            images: images.to_vec(),
+        }
+    }
+
+    pub fn call(&self, logger: &slog::Logger) -> Option<Vec<String>> {
+        if self.resource.kustomize.is_none() && self.resource.helm.is_none() {
+            return Some(Vec::new())
+        }
+
+        let render_dir = self._render_dir();
+
+        // a fresh checkout never has it, unless the repo does
+        if Path::new(&render_dir).exists() {
+            error!(logger, "kube_render_exception: {} is reserved for rendering", RENDER_DIR; "resource" => &self.resource.name);
+
+            return None
+        }
+
+        match fs::create_dir_all(&render_dir) {
+            Ok(_) => {},
+            Err(e) => {
+                error!(logger, "kube_render_exception: {}", e; "dir" => &render_dir);
+
+                return None
+            }
+        };
+
+        let result = match (&self.resource.kustomize, &self.resource.helm) {
+            (Some(kustomize), _) => {
+                self._kustomize(kustomize, logger)
+            },
+            (_, Some(helm)) => {
+                self._helm(helm, logger)
+            },
+            _ => {
+                // nothing to render
+                return Some(Vec::new())
+            }
+        };
+
+        let output = match result {
+            Err(e) => {
+                error!(logger, "kube_render_exception: {}", e; "resource" => &self.resource.name);
+
+                return None
+            },
+            Ok(output) => {
+                output
+            }
+        };
+
+        let file_name = format!("{}/{}.rendered.yml", render_dir, self.resource.name);
+
+        match fs::write(&file_name, output) {
+            Err(e) => {
+                error!(logger, "kube_render_exception: {}", e; "file" => &file_name);
+
+                return None
+            },
+            Ok(_) => {
+                info!(logger, "kube_render_ok"; "file" => &file_name);
+            }
+        };
+
+        Some(vec![file_name])
+    }
+
+    fn _render_dir(&self) -> String {
+        format!("{}/{}", FsRoot::call(&self.id), RENDER_DIR)
+    }
+
+    //
+    // a scratch kustomization that includes the resource's kustomization, images are set on it
+    // so 'kustomize edit' doesn't change the checked out kustomization.yaml
+    //
+
+    fn _kustomize(&self, kustomize: &KubeKustomize, logger: &slog::Logger) -> Result<String> {
+        let path = format!("{}/kustomize", self._render_dir());
+
+        fs::create_dir_all(&path)?;
+
+        let kustomization = format!(
+            "apiVersion: kustomize.config.k8s.io/v1beta1\nkind: Kustomization\nresources:\n  - ../../{}\n",
+            kustomize.path.trim_matches('/'),
+        );
+
+        fs::write(format!("{}/kustomization.yaml", path), kustomization)?;
+
+        for (i, image) in self.images.iter().enumerate() {
+            // kustomize image name override applies to the primary image
+            let image_name = match &kustomize.image {
+                Some(name) if i == 0 => {
+                    name
+                },
+                _ => {
+                    &image.image_name
+                }
+            };
+
+            let image_set = format!("{}={}", image_name, image.reference(self.resource.pin_digest));
+
+            info!(logger, "kustomize_edit_set_image"; "image" => &image_set);
+
+            self._command_output("kustomize", &["edit", "set", "image", &image_set], &path)?;
+        }
+
+        self._command_output("kustomize", &["build", "."], &path)
+    }
+
+    fn _helm(&self, helm: &KubeHelm, logger: &slog::Logger) -> Result<String> {
+        let mut args = vec![
+            "template".to_string(),
+            helm.release.to_string(),
+            helm.chart.to_string(),
+        ];
+
+        if let Some(namespace) = &helm.namespace {
+            args.push("--namespace".to_string());
+            args.push(namespace.to_string());
+        }
+
+        for values_file in helm.values_files.iter() {
+            args.push("--values".to_string());
+            args.push(values_file.to_string());
+        }
+
+        args.push("--set".to_string());
+        args.push(format!("{}={}", helm.image_repository_key, self.vars["image_name"]));
+
+        // tag@digest is a valid image reference, the digest wins
+        let image_tag = match self.vars.get("image_digest") {
+            Some(digest) if self.resource.pin_digest => {
+                format!("{}@{}", self.vars["image_tag"], digest)
+            },
+            _ => {
+                self.vars["image_tag"].to_string()
+            }
+        };
+
+        args.push("--set".to_string());
+        args.push(format!("{}={}", helm.image_tag_key, image_tag));
+
+        // every image by name, e.g. images.worker.repository and images.worker.tag
+        for image in self.images.iter() {
+            let image_tag = match image.image_digest.is_empty() {
+                false if self.resource.pin_digest => {
+                    format!("{}@{}", image.version(), image.image_digest)
+                },
+                _ => {
+                    image.version().to_string()
+                }
+            };
+
+            args.push("--set".to_string());
+            args.push(format!("images.{}.repository={}", image.name, image.image_name));
+            args.push("--set".to_string());
+            args.push(format!("images.{}.tag={}", image.name, image_tag));
+        }
+
+        for (key, value) in helm.set.iter() {
+            let value = match TemplateRender::call(value, &self.vars, self.resource.template_strict) {
+                Err(e) => {
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("helm set {}: {}", key, e)))
+                },
+                Ok(value) => {
+                    value
+                }
+            };
+
+            args.push("--set".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+
+        info!(logger, "helm_template"; "release" => &helm.release, "chart" => &helm.chart);
+
+        let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+        self._command_output("helm", &args, &FsRoot::call(&self.id))
+    }
+
+    fn _command_output(&self, cmd: &str, args: &[&str], dir: &str) -> Result<String> {
+        let output = Command::new(cmd)
+            .args(args)
+            .current_dir(dir)
+            .output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            return Err(Error::new(ErrorKind::Other, format!("{} {}: {}", cmd, output.status, stderr.trim())))
+        }
+
+        match String::from_utf8(output.stdout) {
+            Err(e) => {
+                Err(Error::new(ErrorKind::InvalidData, e))
+            },
+            Ok(s) => {
+                Ok(s)
+            }
+        }
+    }
+}
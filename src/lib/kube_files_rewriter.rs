@@ -1,6 +1,10 @@
+use super::docker::DockerImage;
 use super::fs::FsRoot;
-use super::kube_resource::KubeResourceParser;
+use super::kube_image_set::KubeImageSet;
+use super::kube_resource::KubeResource;
+use super::template::{TemplateRender, TemplateVars};
 
+use slog::{error, info, warn};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
@@ -8,59 +12,30 @@ use std::io::Read;
 #[derive(Debug)]
 pub struct KubeFilesRewriter {
     id: String,
-    image_tag_name: String,
-    pub resource_file: String,
-    pub resource_key: String,
+    vars: TemplateVars,
+    images: Vec<DockerImage>,
+    pub resource: KubeResource,
 }
 
 impl KubeFilesRewriter {
-    pub fn new(id: &String, resource_file: &String, resource_key: &String, image_tag_name: &String) -> KubeFilesRewriter {
+    pub fn new(id: &String, resource: &KubeResource, vars: &TemplateVars, images: &[DockerImage]) -> KubeFilesRewriter {
         KubeFilesRewriter {
             id: id.to_owned(),
-            resource_file: resource_file.to_owned(),
-            resource_key: resource_key.to_owned(),
-            image_tag_name: image_tag_name.to_owned(),
+            resource: resource.clone(),
+            vars: vars.clone(),
+            images: images.to_vec(),
         }
     }
 
-    pub fn call(&self) -> Option<Vec<String>> {
-        let resource_parser = KubeResourceParser::new(
-            &self.resource_file,
-            &self.resource_key,
-        );
-
-        let resource = match resource_parser.call() {
-            Some(value) => {
-                value
-            },
-            None => {
-                return None
-            }
-        };
-
+    pub fn call(&self, logger: &slog::Logger) -> Option<Vec<String>> {
         // get list of all console and resource file names
 
         let mut kube_files = Vec::new();
 
-        match resource.get("console_files") {
-            Some(value) => {
-                for file_name in value.as_array().unwrap().to_vec().iter() {
-                    kube_files.push(file_name.as_str().unwrap().to_owned());
-                }
-            },
-            None => {}
-        };
-
-        match resource.get("resource_files") {
-            Some(value) => {
-                for file_name in value.as_array().unwrap().to_vec().iter() {
-                    kube_files.push(file_name.as_str().unwrap().to_owned());
-                }
-            },
-            None => {}
-        };
+        kube_files.extend(self.resource.console_files.iter().cloned());
+        kube_files.extend(self.resource.resource_files.iter().cloned());
 
-        match self._files_update(kube_files) {
+        match self._files_update(kube_files, logger) {
             Some(files) => {
                 // println!("files_written: {:?}", files);
 
@@ -72,13 +47,20 @@ impl KubeFilesRewriter {
         }
     }
 
-    fn _files_update(&self, files: Vec<String>) -> Option<Vec<String>> {
+    fn _files_update(&self, files: Vec<String>, logger: &slog::Logger) -> Option<Vec<String>> {
         let mut files_copied = Vec::new();
+        let mut image_set_counts = vec![0; self.images.len()];
+        let mut placeholder_seen = vec![false; self.images.len()];
 
         for file_name in files.iter() {
-             match self._file_copy_replace(file_name.to_string()) {
-                 Some(file) => {
+             match self._file_copy_replace(file_name.to_string(), logger) {
+                 Some((file, counts)) => {
                      files_copied.push(file.to_owned());
+
+                     for (i, (count, seen)) in counts.iter().enumerate() {
+                         image_set_counts[i] += count;
+                         placeholder_seen[i] |= seen;
+                     }
                  },
                  None => {
                      println!("file copy error: {}", file_name);
@@ -88,10 +70,25 @@ impl KubeFilesRewriter {
              };
         }
 
+        // an image_set that matches nothing is a config error, the old image would stay deployed,
+        // as is a placeholder used only outside of container images, e.g. in an env value
+        for (i, image) in self.images.iter().enumerate() {
+            if image_set_counts[i] == 0 && (image.image_set.is_some() || placeholder_seen[i]) {
+                error!(logger, "kube_file_image_set_exception: no containers match"; "resource" => &self.resource.name, "image" => &image.name, "placeholder" => &image.placeholder);
+
+                return None
+            }
+        }
+
         Some(files_copied)
     }
 
-    fn _file_copy_replace(&self, file_name: String) -> Option<String> {
+    //
+    // write the file with images and template variables set, returns per image the number of
+    // containers set and whether its placeholder was in the file
+    //
+
+    fn _file_copy_replace(&self, file_name: String, logger: &slog::Logger) -> Option<(String, Vec<(usize, bool)>)> {
         let root_dir = FsRoot::call(&self.id);
         let file_name_current = format!("{}/{}", root_dir, file_name);
         let file_name_latest = format!("{}/{}.latest", root_dir, file_name);
@@ -117,8 +114,80 @@ impl KubeFilesRewriter {
             Ok(_) => {}
         };
 
-        // replace :image_name with image that was just built
-        let input_replaced = input.replace(":image_name", &self.image_tag_name.to_string());
+        // a single image resource sets the containers using the :image_name placeholder, other
+        // placeholders are replaced anywhere in the file unless images are set by container,
+        // longest placeholder first so none is a prefix of another
+        let placeholder_set = self.resource.images.is_empty();
+
+        let mut images_replace: Vec<&DockerImage> = self.images.iter()
+            .filter(|image| image.image_set.is_none() && !image.placeholder.is_empty() && !placeholder_set)
+            .collect();
+
+        images_replace.sort_by_key(|image| std::cmp::Reverse(image.placeholder.len()));
+
+        let mut input_replaced = input;
+
+        for image in images_replace.iter() {
+            input_replaced = input_replaced.replace(&image.placeholder, &image.reference(self.resource.pin_digest));
+        }
+
+        // render template variables, e.g. {{ git_sha }}
+        let input_replaced = match TemplateRender::call(&input_replaced, &self.vars, self.resource.template_strict) {
+            Err(e) => {
+                error!(logger, "kube_file_template_exception: {}", e; "file" => &file_name);
+
+                return None
+            },
+            Ok(value) => {
+                value
+            }
+        };
+
+        let mut image_set_counts = Vec::new();
+        let mut input_replaced = input_replaced;
+
+        for image in self.images.iter() {
+            let reference = image.reference(self.resource.pin_digest);
+
+            let image_set = match &image.image_set {
+                Some(image_set) => {
+                    KubeImageSet::new(image_set, &reference)
+                },
+                None if placeholder_set && !image.placeholder.is_empty() => {
+                    KubeImageSet::placeholder(&image.placeholder, &reference)
+                },
+                None => {
+                    image_set_counts.push((0, false));
+
+                    continue
+                }
+            };
+
+            let seen = !image_set.placeholder.is_empty() && input_replaced.contains(&image_set.placeholder);
+
+            match image_set.call(&input_replaced) {
+                Err(e) if image.image_set.is_none() => {
+                    // e.g. a console file that isn't yaml, the placeholder is replaced anywhere in it
+                    warn!(logger, "kube_file_image_set_fallback: {}", e; "file" => &file_name, "image" => &image.name);
+
+                    image_set_counts.push((input_replaced.matches(&image_set.placeholder).count(), seen));
+
+                    input_replaced = input_replaced.replace(&image_set.placeholder, &reference);
+                },
+                Err(e) => {
+                    error!(logger, "kube_file_image_set_exception: {}", e; "file" => &file_name, "image" => &image.name);
+
+                    return None
+                },
+                Ok((output, count)) => {
+                    info!(logger, "kube_file_image_set_ok"; "file" => &file_name, "image" => &image.name, "containers" => count);
+
+                    image_set_counts.push((count, seen));
+
+                    input_replaced = output;
+                }
+            };
+        }
 
         match fs::write(&file_name_latest, input_replaced.to_string()) {
             Err(_) => {
@@ -127,7 +196,7 @@ impl KubeFilesRewriter {
             Ok(_) => {}
         };
 
-        Some(file_name_latest)
+        Some((file_name_latest, image_set_counts))
     }
 
 }
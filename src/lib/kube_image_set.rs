@@ -0,0 +1,387 @@
+use super::kube_resource::KubeImageSetConfig;
+
+use serde::Deserialize;
+use std::io::{Error, ErrorKind, Result};
+
+// pod spec paths in workload manifests, e.g. deployments use spec.template.spec
+const POD_SPEC_PATHS: [&[&str]; 3] = [
+    &["spec"],
+    &["spec", "template", "spec"],
+    &["spec", "jobTemplate", "spec", "template", "spec"],
+];
+
+const CONTAINER_KEYS: [&str; 2] = ["containers", "initContainers"];
+
+//
+// set container images in (multi-document) kubernetes yaml, matching containers by
+// name, by image repository or by a placeholder image, e.g. image: :image_name
+//
+
+#[derive(Debug)]
+pub struct KubeImageSet {
+    pub config: KubeImageSetConfig,
+    pub placeholder: String,
+    pub image: String,
+}
+
+impl KubeImageSet {
+    pub fn new(config: &KubeImageSetConfig, image: &str) -> KubeImageSet {
+        KubeImageSet {
+            config: config.clone(),
+            placeholder: "".to_string(),
+            image: image.to_string(),
+        }
+    }
+
+    // containers whose image is the placeholder, other occurrences, e.g. in comments, are kept
+    pub fn placeholder(placeholder: &str, image: &str) -> KubeImageSet {
+        KubeImageSet {
+            config: KubeImageSetConfig::default(),
+            placeholder: placeholder.to_string(),
+            image: image.to_string(),
+        }
+    }
+
+    // returns the updated yaml and the number of containers updated
+    pub fn call(&self, input: &str) -> Result<(String, usize)> {
+        let (serialized, count) = self._serialized_update(input)?;
+
+        // image lines are edited in place so comments and formatting are kept, manifests the
+        // line scan doesn't understand, e.g. flow style container lists, are re-serialized
+        let (edited, edited_count) = self._lines_update(input);
+
+        if edited_count == count {
+            return Ok((edited, count))
+        }
+
+        Ok((serialized, count))
+    }
+
+    fn _serialized_update(&self, input: &str) -> Result<(String, usize)> {
+        let mut documents = Vec::new();
+        let mut count = 0;
+
+        for document in serde_yaml::Deserializer::from_str(input) {
+            let mut value = match serde_yaml::Value::deserialize(document) {
+                Err(e) => {
+                    return Err(Error::new(ErrorKind::InvalidData, e))
+                },
+                Ok(value) => {
+                    value
+                }
+            };
+
+            // skip empty documents, e.g. a trailing '---'
+            if value.is_null() {
+                continue
+            }
+
+            count += self._document_update(&mut value);
+
+            match serde_yaml::to_string(&value) {
+                Err(e) => {
+                    return Err(Error::new(ErrorKind::InvalidData, e))
+                },
+                Ok(s) => {
+                    documents.push(s);
+                }
+            };
+        }
+
+        Ok((documents.join("---\n"), count))
+    }
+
+    //
+    // scan block style 'containers:' and 'initContainers:' lists, replacing the image value of
+    // matching containers and keeping quotes and trailing comments
+    //
+
+    fn _lines_update(&self, input: &str) -> (String, usize) {
+        let mut lines: Vec<String> = input.split_inclusive('\n').map(|s| s.to_string()).collect();
+        let mut count = 0;
+
+        // indent of the containers key, the list dash and the container's keys
+        let mut list_indent: Option<usize> = None;
+        let mut dash_indent: Option<usize> = None;
+        let mut key_indent = 0;
+        let mut container = KubeImageSetLines::default();
+
+        for i in 0..lines.len() {
+            let line = lines[i].trim_end().to_string();
+            let content = line.trim_start();
+            let indent = line.len() - content.len();
+
+            if content.is_empty() || content.starts_with('#') {
+                continue
+            }
+
+            if let Some(containers_indent) = list_indent {
+                let is_dash = content == "-" || content.starts_with("- ");
+
+                let in_list = indent > containers_indent || (indent == containers_indent && is_dash);
+
+                if !in_list {
+                    count += self._container_update(&mut lines, &container);
+                    container = KubeImageSetLines::default();
+                    list_indent = None;
+                    dash_indent = None;
+                } else if is_dash && dash_indent.is_none_or(|dash| dash == indent) {
+                    count += self._container_update(&mut lines, &container);
+                    container = KubeImageSetLines::default();
+                    dash_indent = Some(indent);
+
+                    let item = content[1..].trim_start();
+
+                    key_indent = indent + (content.len() - item.len());
+
+                    container.key_read(i, key_indent, item);
+
+                    continue
+                } else {
+                    if indent == key_indent {
+                        container.key_read(i, indent, content);
+                    }
+
+                    continue
+                }
+            }
+
+            // the key can be the first key of a list item, e.g. '- containers:'
+            let key = content.strip_prefix("- ").unwrap_or(content).trim_start();
+            let key_offset = content.len() - key.len();
+
+            let key = key.split(" #").next().unwrap_or(key).trim_end();
+
+            if key == "containers:" || key == "initContainers:" {
+                list_indent = Some(indent + key_offset);
+            }
+        }
+
+        count += self._container_update(&mut lines, &container);
+
+        (lines.concat(), count)
+    }
+
+    fn _container_update(&self, lines: &mut [String], container: &KubeImageSetLines) -> usize {
+        let (line, value_start, value_end) = match container.image {
+            None => {
+                return 0
+            },
+            Some(image) => {
+                image
+            }
+        };
+
+        let quoted = &lines[line][value_start..value_end];
+        let image = quoted.trim_matches(|c| c == '"' || c == '\'');
+
+        if !self._container_match_name(&container.name, image) {
+            return 0
+        }
+
+        let quote = if quoted.starts_with('"') { "\"" } else if quoted.starts_with('\'') { "'" } else { "" };
+
+        let updated = format!("{}{}{}{}{}", &lines[line][..value_start], quote, self.image, quote, &lines[line][value_end..]);
+
+        lines[line] = updated;
+
+        1
+    }
+
+    fn _container_match_name(&self, name: &str, image: &str) -> bool {
+        if !self.placeholder.is_empty() && image == self.placeholder {
+            return true
+        }
+
+        if self.config.containers.iter().any(|s| s == name) {
+            return true
+        }
+
+        match &self.config.repository {
+            Some(repository) => {
+                KubeImageSet::repository(image) == repository
+            },
+            None => {
+                false
+            }
+        }
+    }
+
+    fn _document_update(&self, document: &mut serde_yaml::Value) -> usize {
+        let mut count = 0;
+
+        // e.g. kind: List
+        if let Some(items) = document.get_mut("items").and_then(|v| v.as_sequence_mut()) {
+            for item in items.iter_mut() {
+                count += self._document_update(item);
+            }
+        }
+
+        for path in POD_SPEC_PATHS.iter() {
+            let mut pod_spec = Some(&mut *document);
+
+            for key in path.iter() {
+                pod_spec = pod_spec.and_then(|v| v.get_mut(*key));
+            }
+
+            let pod_spec = match pod_spec {
+                None => {
+                    continue
+                },
+                Some(value) => {
+                    value
+                }
+            };
+
+            for key in CONTAINER_KEYS.iter() {
+                let containers = match pod_spec.get_mut(*key).and_then(|v| v.as_sequence_mut()) {
+                    None => {
+                        continue
+                    },
+                    Some(containers) => {
+                        containers
+                    }
+                };
+
+                for container in containers.iter_mut() {
+                    if container.is_mapping() && self._container_match(container) {
+                        container["image"] = serde_yaml::Value::String(self.image.to_string());
+
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        count
+    }
+
+    fn _container_match(&self, container: &serde_yaml::Value) -> bool {
+        let name = container.get("name").and_then(|v| v.as_str()).unwrap_or("");
+        let image = container.get("image").and_then(|v| v.as_str()).unwrap_or("");
+
+        self._container_match_name(name, image)
+    }
+
+    // image without tag or digest, e.g. gcr.io/project/api:1.0 -> gcr.io/project/api
+    pub fn repository(image: &str) -> &str {
+        let image = image.split('@').next().unwrap_or(image);
+
+        match image.rfind(':') {
+            Some(i) if !image[i..].contains('/') => {
+                &image[..i]
+            },
+            _ => {
+                image
+            }
+        }
+    }
+}
+
+// name and image value position of a container list item, read line by line
+#[derive(Debug, Default)]
+struct KubeImageSetLines {
+    name: String,
+    image: Option<(usize, usize, usize)>,  // line, value start and end
+}
+
+impl KubeImageSetLines {
+    fn key_read(&mut self, line: usize, indent: usize, content: &str) {
+        let (key, value) = match content.split_once(':') {
+            None => {
+                return
+            },
+            Some((key, value)) => {
+                (key.trim(), value)
+            }
+        };
+
+        let value_trimmed = value.trim_start();
+        let value_start = indent + key.len() + 1 + (value.len() - value_trimmed.len());
+
+        // quoted values end at the closing quote, plain values at a comment
+        let value_len = match value_trimmed.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                match value_trimmed[1..].find(quote) {
+                    Some(end) => {
+                        end + 2
+                    },
+                    None => {
+                        return
+                    }
+                }
+            },
+            _ => {
+                value_trimmed.split(" #").next().unwrap_or("").trim_end().len()
+            }
+        };
+
+        if value_len == 0 {
+            return
+        }
+
+        match key {
+            "name" => {
+                self.name = value_trimmed[..value_len].trim_matches(|c| c == '"' || c == '\'').to_string();
+            },
+            "image" => {
+                self.image = Some((line, value_start, value_start + value_len));
+            },
+            _ => {}
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPLOYMENT: &str = "\
+apiVersion: apps/v1
+kind: Deployment
+spec:
+  template:
+    spec:
+      containers:
+        - name: api
+          # built from :image_name
+          image: \":image_name\"  # set by deploybot
+          env:
+            - name: IMAGE
+              value: :image_name
+        - name: proxy
+          image: envoyproxy/envoy:v1.30
+";
+
+    #[test]
+    fn placeholder_sets_container_images_only() {
+        let (output, count) = KubeImageSet::placeholder(":image_name", "gcr.io/project/api@sha256:abc").call(DEPLOYMENT).unwrap();
+
+        assert_eq!(count, 1);
+        assert!(output.contains("image: \"gcr.io/project/api@sha256:abc\"  # set by deploybot\n"));
+        assert!(output.contains("# built from :image_name\n"));
+        assert!(output.contains("value: :image_name\n"));
+        assert!(output.contains("image: envoyproxy/envoy:v1.30\n"));
+    }
+
+    #[test]
+    fn placeholder_without_containers() {
+        let input = "apiVersion: v1\nkind: ConfigMap\ndata:\n  image: :image_name\n";
+
+        assert_eq!(KubeImageSet::placeholder(":image_name", "gcr.io/project/api:1").call(input).unwrap(), (input.to_string(), 0));
+    }
+
+    #[test]
+    fn repository_and_name_match() {
+        let config = KubeImageSetConfig {
+            containers: vec!["proxy".to_string()],
+            repository: None,
+        };
+
+        let (output, count) = KubeImageSet::new(&config, "envoyproxy/envoy:v1.31").call(DEPLOYMENT).unwrap();
+
+        assert_eq!(count, 1);
+        assert!(output.contains("image: envoyproxy/envoy:v1.31\n"));
+        assert_eq!(KubeImageSet::repository("gcr.io:443/project/api:1.0@sha256:abc"), "gcr.io:443/project/api");
+    }
+}
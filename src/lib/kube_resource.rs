@@ -1,13 +1,416 @@
+use super::config::BuildBackend;
 use super::fs::FsRoot;
 
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+const WATCH_SLEEP_DEFAULT: u64 = 20;
+const WATCH_WAIT_DEFAULT: u64 = 60;
+
+//
+// resources.toml schema, e.g.
+//
+// [defaults]
+// docker_file = "Dockerfile"
+//
+// [[resources]]
+// name = "api-base"
+// abstract = true
+// image_name = "gcr.io/project/api"
+// resource_files = ["kubernetes/api-deployment.yml"]
+//
+// [[resources]]
+// name = "api-staging"
+// extends = "api-base"
+// replace = ["resource_files"]  # instead of appending to the inherited list
+// resource_files = ["kubernetes/api-staging.yml"]
+// kube_context = "gke_project_staging"
+// git_depth = 1  # monorepos, fetch only the deploy commit
+// sparse_paths = ["services/api", "libs/common"]
+// git_submodules = true
+// git_lfs = true
+//
+// [resources.vars]
+// replicas = 2  # strings, numbers and bools
+//
+// [[resources.images]]  # instead of docker_file and image_name, to build several images
+// name = "worker"
+// docker_file = "Dockerfile.worker"
+// image_name = "gcr.io/project/worker"
+// placeholder = ":worker_image"
+//
+// [resources.build]
+// args = { "GIT_SHA" = "{{ git_sha }}" }
+// target = "release"
+// builder = "kaniko"  # or buildah, docker
+// cache_ref = "gcr.io/project/api:buildcache"
+//
+// [resources.image_set]  # set container images in resource_files without the :image_name placeholder
+// containers = ["api"]
+//
+// [resources.helm]  # or [resources.kustomize], rendered in addition to resource_files
+// chart = "charts/api"
+// release = "api"
+// values_files = ["charts/api/values-staging.yaml"]
+//
+// [[resources.watches]]
+// cmd = "kubectl rollout status deployment/api --context=gke_project_staging"
+//
+// resources inherit from [defaults] and then from the resource they extend, tables are
+// merged key by key, arrays are appended to (skipping duplicates) and other values replaced
+//
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeResource {
+    pub name: String,
+    #[serde(default)]
+    pub docker_file: String,  // single image resources, or use 'images'
+    #[serde(default)]
+    pub image_name: String,
+    #[serde(default)]
+    pub images: Vec<KubeImage>,
+    #[serde(default)]
+    pub parallel_builds: bool,  // build 'images' concurrently
+    pub git_depth: Option<i32>,  // shallow clone with this many commits, full history by default
+    #[serde(default)]
+    pub sparse_paths: Vec<String>,  // check out only these paths, plus the resources file, docker files and manifests
+    #[serde(default)]
+    pub git_submodules: bool,  // update submodules recursively
+    #[serde(default)]
+    pub git_lfs: bool,  // fetch lfs objects with 'git lfs pull'
+    pub kube_context: String,
+    #[serde(default)]
+    pub console_files: Vec<String>,
+    #[serde(default)]
+    pub resource_files: Vec<String>,
+    #[serde(default)]
+    pub watches: Vec<KubeWatch>,
+    #[serde(default, deserialize_with = "KubeResource::vars_deserialize")]
+    pub vars: BTreeMap<String, String>,  // manifest template variables, e.g. {{ vars.replicas }}
+    #[serde(default = "KubeResource::template_strict_default")]
+    pub template_strict: bool,  // fail on undefined template variables, else they are left as is
+    pub kustomize: Option<KubeKustomize>,
+    pub helm: Option<KubeHelm>,
+    pub image_set: Option<KubeImageSetConfig>,
+    #[serde(default = "KubeResource::pin_digest_default")]
+    pub pin_digest: bool,  // reference images as name@sha256:... in manifests
+    #[serde(default)]
+    pub build: KubeBuild,
+}
+
+// one of several images built for a resource, e.g. api, worker, migrations
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeImage {
+    pub name: String,
+    pub docker_file: String,
+    pub image_name: String,
+    pub placeholder: Option<String>,  // replaced in manifests, defaults to ':<name>_image'
+    pub build: Option<KubeBuild>,  // defaults to the resource build options
+    pub image_set: Option<KubeImageSetConfig>,
+}
+
+// docker build options, string values are rendered as templates, e.g. "{{ git_sha }}"
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeBuild {
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,  // --build-arg
+    pub target: Option<String>,
+    pub context: Option<String>,  // build context dir, defaults to the checkout root
+    pub platform: Option<String>,  // e.g. linux/amd64
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,  // added to the default oci labels
+    #[serde(default)]
+    pub secrets: Vec<KubeBuildSecret>,
+    #[serde(default)]
+    pub rebuild: bool,  // always build, even if the registry has an image for the same sha and options
+    pub builder: Option<BuildBackend>,  // docker, buildah or kaniko, defaults to deploybot's docker.builder
+    #[serde(default)]
+    pub cache_from_previous: bool,  // use the image of the last completed deploy as a docker build cache
+    pub cache_ref: Option<String>,  // registry build cache, e.g. gcr.io/project/api:buildcache
+}
+
+// buildkit secret mount, 'source' names a secret in deploybot's docker.secrets config
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeBuildSecret {
+    pub id: String,
+    pub source: String,
+}
+
+// set container images by parsing the manifests instead of replacing the :image_name placeholder
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeImageSetConfig {
+    #[serde(default)]
+    pub containers: Vec<String>,  // container names
+    pub repository: Option<String>,  // or containers using this image repository, e.g. gcr.io/project/api
+}
+
+// render manifests with 'kustomize build', overriding the image with 'kustomize edit set image'
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeKustomize {
+    pub path: String,  // kustomization dir, e.g. kubernetes/overlays/staging
+    pub image: Option<String>,  // image name used in the manifests, defaults to the resource image_name
+}
+
+// render manifests with 'helm template'
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeHelm {
+    pub chart: String,  // chart dir or oci:// reference, e.g. charts/api
+    pub release: String,
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub values_files: Vec<String>,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,  // extra --set values
+    #[serde(default = "KubeHelm::image_repository_key_default")]
+    pub image_repository_key: String,
+    #[serde(default = "KubeHelm::image_tag_key_default")]
+    pub image_tag_key: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KubeWatch {
+    pub cmd: String,
+    #[serde(default = "KubeWatch::sleep_default")]
+    pub sleep: u64,
+    #[serde(default = "KubeWatch::wait_default")]
+    pub wait: u64,
+}
+
+#[derive(Debug)]
+pub struct KubeResourceError {
+    pub file: String,
+    pub resource: Option<String>,
+    pub field: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
 #[derive(Debug)]
 pub struct KubeResourceParser {
     pub resource_file: String,
     pub resource_key: String,
 }
 
+pub struct KubeResourceLine {}
+
+pub struct KubeResourceMerge {}
+
 pub struct KubeResourceResolve {}
 
+impl KubeWatch {
+    fn sleep_default() -> u64 {
+        WATCH_SLEEP_DEFAULT
+    }
+
+    fn wait_default() -> u64 {
+        WATCH_WAIT_DEFAULT
+    }
+}
+
+// source text of the vars tables, floats are rendered as written, e.g. 1.0 rather than 1
+#[derive(Default, Deserialize)]
+struct KubeVarsSource {
+    #[serde(default)]
+    defaults: KubeVarsSourceTable,
+    #[serde(default)]
+    resources: Vec<KubeVarsSourceTable>,
+}
+
+#[derive(Default, Deserialize)]
+struct KubeVarsSourceTable {
+    #[serde(default)]
+    vars: BTreeMap<String, toml::Spanned<toml::Value>>,
+}
+
+// a template variable value, numbers and bools are rendered as written, e.g. replicas = 2
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KubeVarValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+impl KubeResource {
+    fn pin_digest_default() -> bool {
+        true
+    }
+
+    fn template_strict_default() -> bool {
+        true
+    }
+
+    fn vars_deserialize<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error> {
+        let vars: BTreeMap<String, KubeVarValue> = BTreeMap::deserialize(deserializer)?;
+
+        Ok(vars.into_iter().map(|(key, value)| {
+            let value = match value {
+                KubeVarValue::String(s) => s,
+                KubeVarValue::Integer(n) => n.to_string(),
+                KubeVarValue::Float(n) => n.to_string(),
+                KubeVarValue::Boolean(b) => b.to_string(),
+            };
+
+            (key, value)
+        }).collect())
+    }
+
+    // paths a sparse checkout needs besides sparse_paths
+    pub fn checkout_paths(&self) -> Vec<String> {
+        let mut paths = self.sparse_paths.clone();
+
+        for image in self.images_list().iter() {
+            paths.push(image.docker_file.to_string());
+        }
+
+        paths.extend(self.console_files.iter().cloned());
+        paths.extend(self.resource_files.iter().cloned());
+
+        if let Some(kustomize) = &self.kustomize {
+            paths.push(kustomize.path.to_string());
+        }
+
+        if let Some(helm) = &self.helm {
+            if !helm.chart.contains("://") {
+                paths.push(helm.chart.to_string());
+            }
+
+            paths.extend(helm.values_files.iter().cloned());
+        }
+
+        paths
+    }
+
+    //
+    // images to build, a single image resource is one image using the :image_name placeholder
+    // and the resource build options and image_set, the first image is the resource's primary image
+    //
+
+    pub fn images_list(&self) -> Vec<KubeImage> {
+        if !self.images.is_empty() {
+            return self.images.iter().enumerate().map(|(i, image)| {
+                let mut image = image.clone();
+
+                if image.placeholder.is_none() {
+                    image.placeholder = Some(format!(":{}_image", image.name));
+                }
+
+                if image.build.is_none() {
+                    image.build = Some(self.build.clone());
+                }
+
+                if i == 0 && image.image_set.is_none() {
+                    image.image_set = self.image_set.clone();
+                }
+
+                image
+            }).collect()
+        }
+
+        vec![
+            KubeImage {
+                name: self.name.to_string(),
+                docker_file: self.docker_file.to_string(),
+                image_name: self.image_name.to_string(),
+                placeholder: Some(":image_name".to_string()),
+                build: Some(self.build.clone()),
+                image_set: self.image_set.clone(),
+            }
+        ]
+    }
+}
+
+impl KubeHelm {
+    fn image_repository_key_default() -> String {
+        "image.repository".to_string()
+    }
+
+    fn image_tag_key_default() -> String {
+        "image.tag".to_string()
+    }
+}
+
+impl KubeResourceError {
+    pub fn new(file: &str, message: &str) -> KubeResourceError {
+        KubeResourceError {
+            file: file.to_string(),
+            resource: None,
+            field: None,
+            line: None,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for KubeResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+
+        if let Some(resource) = &self.resource {
+            write!(f, " resource '{}'", resource)?;
+        }
+
+        if let Some(field) = &self.field {
+            write!(f, " field '{}'", field)?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for KubeResourceError {}
+
+impl KubeVarsSource {
+    // replace float vars with their source text, before defaults and extends are merged
+    fn call(toml_string: &str, toml_object: &mut toml::Table) {
+        // the table parsed, an invalid vars table is reported when the resource is deserialized
+        let source: KubeVarsSource = match toml::from_str(toml_string) {
+            Err(_) => {
+                return
+            },
+            Ok(source) => {
+                source
+            }
+        };
+
+        if let Some(vars) = toml_object.get_mut("defaults").and_then(|value| value.get_mut("vars")).and_then(|value| value.as_table_mut()) {
+            source.defaults.keep(toml_string, vars);
+        }
+
+        if let Some(list) = toml_object.get_mut("resources").and_then(|value| value.as_array_mut()) {
+            for (value, table) in list.iter_mut().zip(source.resources.iter()) {
+                if let Some(vars) = value.get_mut("vars").and_then(|value| value.as_table_mut()) {
+                    table.keep(toml_string, vars);
+                }
+            }
+        }
+    }
+}
+
+impl KubeVarsSourceTable {
+    fn keep(&self, toml_string: &str, vars: &mut toml::Table) {
+        for (key, value) in self.vars.iter() {
+            if value.get_ref().is_float() {
+                vars.insert(key.to_string(), toml::Value::String(toml_string[value.span()].trim().to_string()));
+            }
+        }
+    }
+}
+
 impl KubeResourceParser {
     pub fn new(resource_file: &String, resource_key: &String) -> KubeResourceParser {
         KubeResourceParser {
@@ -16,72 +419,400 @@ impl KubeResourceParser {
         }
     }
 
-    pub fn call(&self) -> Option<toml::Value> {
-        let toml_string = match std::fs::read_to_string(self.resource_file.to_string()) {
-            Err(_) => {
-                return None
+    pub fn call(&self) -> Result<KubeResource, KubeResourceError> {
+        let resources = self.parse()?;
+
+        // iterate resources to find 'resource_key' match
+
+        match resources.into_iter().find(|resource| resource.name == self.resource_key) {
+            Some(resource) => {
+                Ok(resource)
+            },
+            None => {
+                let mut error = KubeResourceError::new(&self.resource_file, "resource not found, or is abstract");
+                error.resource = Some(self.resource_key.to_string());
+
+                Err(error)
+            }
+        }
+    }
+
+    // parse and validate all deployable resources in the file
+    pub fn parse(&self) -> Result<Vec<KubeResource>, KubeResourceError> {
+        let toml_string = match std::fs::read_to_string(&self.resource_file) {
+            Err(e) => {
+                return Err(KubeResourceError::new(&self.resource_file, &e.to_string()))
             },
             Ok(value) => {
                 value
             }
+        };
 
+        let mut toml_object: toml::Table = match toml::from_str(&toml_string) {
+            Err(e) => {
+                return Err(self._toml_error(&toml_string, e))
+            },
+            Ok(value) => {
+                value
+            }
         };
 
-        // println!("toml_string: {:?}", toml_string);
+        KubeVarsSource::call(&toml_string, &mut toml_object);
 
-        match self._toml_parse(toml_string) {
-            Some(value) => {
-                // println!("value: {:?}", value);
+        for key in toml_object.keys() {
+            if key != "defaults" && key != "resources" {
+                let mut error = KubeResourceError::new(&self.resource_file, "unknown table, expected one of `defaults`, `resources`");
+                error.field = Some(key.to_string());
+
+                return Err(error)
+            }
+        }
+
+        let defaults = match toml_object.get("defaults") {
+            None => {
+                toml::Table::new()
+            },
+            Some(toml::Value::Table(table)) if table.contains_key("abstract") => {
+                let mut error = KubeResourceError::new(&self.resource_file, "abstract can't be a default, it would apply to every resource");
+                error.field = Some("defaults.abstract".to_string());
+                error.line = toml_string.lines().position(|line| line.trim() == "[defaults]").map(|i| i + 1);
 
-                return Some(value)
+                return Err(error)
+            },
+            Some(toml::Value::Table(table)) => {
+                table.clone()
             },
+            Some(_) => {
+                let mut error = KubeResourceError::new(&self.resource_file, "defaults must be a table");
+                error.field = Some("defaults".to_string());
+
+                return Err(error)
+            }
+        };
+
+        let resources_list = match toml_object.get("resources").and_then(|value| value.as_array()) {
             None => {
-                return None
+                return Err(KubeResourceError::new(&self.resource_file, "missing [[resources]] list"))
+            },
+            Some(list) => {
+                list
             }
         };
+
+        let mut resources_raw = Vec::new();
+
+        for (i, value) in resources_list.iter().enumerate() {
+            let table = value.as_table().cloned().unwrap_or_default();
+
+            let name = match table.get("name").and_then(|v| v.as_str()) {
+                None => {
+                    let mut error = KubeResourceError::new(&self.resource_file, "missing field `name`");
+                    error.resource = Some(format!("#{}", i + 1));
+                    error.field = Some("name".to_string());
+
+                    return Err(error)
+                },
+                Some(name) => {
+                    name.to_string()
+                }
+            };
+
+            if resources_raw.iter().any(|(n, _)| n == &name) {
+                return Err(self._resource_error(&toml_string, &name, "name", "duplicate resource name"))
+            }
+
+            resources_raw.push((name, table));
+        }
+
+        let mut resources = Vec::new();
+
+        for (name, _) in resources_raw.iter() {
+            let mut merged = self._resource_resolve(&toml_string, &defaults, &resources_raw, name, &mut Vec::new())?;
+
+            // abstract resources are only used with 'extends' and can't be deployed
+            if merged.remove("abstract").and_then(|v| v.as_bool()) == Some(true) {
+                continue
+            }
+
+            match toml::Value::Table(merged).try_into::<KubeResource>() {
+                Err(e) => {
+                    // error display ends with the key path, e.g. "in `watches.0.cmd`"
+                    let error_string = e.to_string();
+                    let field = error_string.lines().last()
+                        .filter(|line| line.starts_with("in `"))
+                        .map(|line| line.trim_start_matches("in `").trim_end_matches('`').to_string())
+                        .or(e.message().split('`').nth(1).filter(|_| e.message().contains(" field `")).map(|s| s.to_string()))
+                        .unwrap_or("".to_string());
+
+                    return Err(self._resource_error(&toml_string, name, &field, e.message()))
+                },
+                Ok(resource) => {
+                    if resource.kustomize.is_some() && resource.helm.is_some() {
+                        return Err(self._resource_error(&toml_string, name, "helm", "use only one of kustomize, helm"))
+                    }
+
+                    if resource.images.is_empty() && (resource.docker_file.is_empty() || resource.image_name.is_empty()) {
+                        return Err(self._resource_error(&toml_string, name, "docker_file", "docker_file and image_name, or images, are required"))
+                    }
+
+                    if !resource.images.is_empty() && (!resource.docker_file.is_empty() || !resource.image_name.is_empty()) {
+                        return Err(self._resource_error(&toml_string, name, "images", "use docker_file and image_name, or images, not both"))
+                    }
+
+                    if resource.git_depth.map(|depth| depth < 1) == Some(true) {
+                        return Err(self._resource_error(&toml_string, name, "git_depth", "must be at least 1"))
+                    }
+
+                    let images = resource.images_list();
+
+                    for (i, image) in images.iter().enumerate() {
+                        if images[..i].iter().any(|other| other.name == image.name) {
+                            return Err(self._resource_error(&toml_string, name, "images", &format!("duplicate image name '{}'", image.name)))
+                        }
+
+                        if let Some(build) = &image.build {
+                            if build.builder == Some(BuildBackend::Kaniko) && !build.secrets.is_empty() {
+                                return Err(self._resource_error(&toml_string, name, "build", "kaniko does not support build secrets"))
+                            }
+                        }
+
+                        if let Some(image_set) = &image.image_set {
+                            if image_set.containers.is_empty() && image_set.repository.is_none() {
+                                return Err(self._resource_error(&toml_string, name, "image_set", "containers or repository is required"))
+                            }
+                        }
+                    }
+
+                    resources.push(resource);
+                }
+            };
+        }
+
+        Ok(resources)
     }
 
-    fn _toml_parse(&self, toml_string: String) -> Option<toml::Value> {
-        let toml_object: toml::Value = match toml::from_str(&toml_string) {
-            Err(_) => {
-                return None
+    //
+    // merge defaults, the 'extends' chain and the resource's own values
+    //
+
+    fn _resource_resolve(&self, toml_string: &str, defaults: &toml::Table, resources_raw: &[(String, toml::Table)], name: &str, chain: &mut Vec<String>) -> Result<toml::Table, KubeResourceError> {
+        if chain.iter().any(|n| n == name) {
+            chain.push(name.to_string());
+
+            return Err(self._resource_error(toml_string, &chain[0], "extends", &format!("circular extends: {}", chain.join(" -> "))))
+        }
+
+        chain.push(name.to_string());
+
+        let mut own = match resources_raw.iter().find(|(n, _)| n == name) {
+            None => {
+                let message = format!("extends unknown resource '{}'", name);
+
+                return Err(self._resource_error(toml_string, &chain[chain.len() - 2], "extends", &message))
             },
-            Ok(value) => {
-                value
+            Some((_, table)) => {
+                table.clone()
             }
         };
 
-        // println!("toml_object: {:?}", toml_object);
+        // inherited arrays and tables these keys replace instead of merging with, e.g. replace = ["watches"]
+        let replace = match own.remove("replace") {
+            None => {
+                Vec::new()
+            },
+            Some(toml::Value::Array(keys)) if keys.iter().all(|key| key.is_str()) => {
+                keys.iter().filter_map(|key| key.as_str().map(|s| s.to_string())).collect()
+            },
+            Some(_) => {
+                return Err(self._resource_error(toml_string, name, "replace", "replace must be a list of field names"))
+            }
+        };
 
-        let resources_list = match toml_object.get("resources") {
+        let mut base = match own.remove("extends") {
             None => {
-                return None
+                defaults.clone()
             },
-            Some(value) => {
-                value.as_array().unwrap()
+            Some(toml::Value::String(parent)) => {
+                let mut base = self._resource_resolve(toml_string, defaults, resources_raw, &parent, chain)?;
+
+                // abstract is not inherited
+                base.remove("abstract");
+
+                base
+            },
+            Some(_) => {
+                return Err(self._resource_error(toml_string, name, "extends", "extends must be a resource name"))
             }
         };
 
-        // iterate resources_list to find 'resource_key' match
+        for key in replace.iter() {
+            if !own.contains_key(key) {
+                return Err(self._resource_error(toml_string, name, "replace", &format!("replaces '{}' but doesn't set it", key)))
+            }
+
+            base.remove(key);
+        }
 
-        let resource = resources_list.iter().find(
-            |&resource| resource.get("name").unwrap().as_str().unwrap() == self.resource_key
-        );
+        Ok(KubeResourceMerge::table(base, own))
+    }
 
-        // println!("resource: {:?}", resource);
+    fn _resource_error(&self, toml_string: &str, resource: &str, field: &str, message: &str) -> KubeResourceError {
+        let mut error = KubeResourceError::new(&self.resource_file, message);
+        error.resource = Some(resource.to_string());
+        error.line = KubeResourceLine::call(toml_string, resource, field);
 
-        match resource {
-            Some(value) => {
-                return Some(value.clone())
-            },
+        if !field.is_empty() {
+            error.field = Some(field.to_string());
+        }
+
+        error
+    }
+
+    //
+    // map a toml error span back to the line, resource and field it came from
+    //
+
+    fn _toml_error(&self, toml_string: &str, e: toml::de::Error) -> KubeResourceError {
+        let mut error = KubeResourceError::new(&self.resource_file, e.message());
+
+        let span = match e.span() {
             None => {
-                return None
+                return error
+            },
+            Some(span) => {
+                span
             }
+        };
+
+        let before = &toml_string[..span.start];
+        let line = before.matches('\n').count() + 1;
+
+        error.line = Some(line);
+
+        // field from 'unknown field `x`' / 'missing field `x`' messages, or the key on the error line
+        let message_field = e.message().split('`').nth(1).filter(|_| e.message().contains(" field `"));
+        let line_text = toml_string.lines().nth(line - 1).unwrap_or("");
+        let line_field = line_text.split('=').next().filter(|_| line_text.contains('=')).map(|s| s.trim());
+
+        error.field = message_field.or(line_field).map(|s| s.to_string());
+
+        // resource is the last [[resources]] table started at or before the error line
+        let line_end = toml_string[span.start..].find('\n').map(|i| span.start + i).unwrap_or(toml_string.len());
+        let index = toml_string[..line_end].lines().filter(|l| l.trim() == "[[resources]]").count();
+
+        if index > 0 {
+            error.resource = KubeResourceLine::name(toml_string, index).or(Some(format!("#{}", index)));
         }
+
+        error
     }
 
 }
 
+impl KubeResourceMerge {
+    // deep merge 'over' into 'base'
+    pub fn table(mut base: toml::Table, over: toml::Table) -> toml::Table {
+        for (key, value) in over.into_iter() {
+            let merged = match base.remove(&key) {
+                None => {
+                    value
+                },
+                Some(base_value) => {
+                    KubeResourceMerge::value(base_value, value)
+                }
+            };
+
+            base.insert(key, merged);
+        }
+
+        base
+    }
+
+    pub fn value(base: toml::Value, over: toml::Value) -> toml::Value {
+        match (base, over) {
+            (toml::Value::Table(base), toml::Value::Table(over)) => {
+                toml::Value::Table(KubeResourceMerge::table(base, over))
+            },
+            (toml::Value::Array(mut base), toml::Value::Array(over)) => {
+                for value in over.into_iter() {
+                    if !base.contains(&value) {
+                        base.push(value);
+                    }
+                }
+
+                toml::Value::Array(base)
+            },
+            (_, over) => {
+                over
+            }
+        }
+    }
+}
+
+impl KubeResourceLine {
+    //
+    // find the line for a resource field, searching the resource's table for the field's key
+    // and falling back to the resource's name
+    //
+
+    pub fn call(toml_string: &str, resource: &str, field: &str) -> Option<usize> {
+        let lines: Vec<&str> = toml_string.lines().collect();
+
+        let name_line = lines.iter().position(|line| {
+            KubeResourceLine::_key_value(line) == Some(("name", resource))
+        })?;
+
+        let key = field.split('.').next().unwrap_or("");
+
+        // resource table ends at the next [[resources]]
+        for (i, line) in lines.iter().enumerate().skip(name_line) {
+            if i > name_line && line.trim() == "[[resources]]" {
+                break
+            }
+
+            let line_key = line.split_once('=').map_or(*line, |(key, _)| key).trim();
+            let line_table = line.trim().trim_start_matches("[[resources.").trim_start_matches("[resources.");
+
+            if !key.is_empty() && (line_key == key || line_table.starts_with(&format!("{}]", key))) {
+                return Some(i + 1)
+            }
+        }
+
+        Some(name_line + 1)
+    }
+
+    //
+    // name of the index-th [[resources]] table, read from the text so it works for files with
+    // syntax errors, the name is the table's 'name' key before any sub table
+    //
+
+    pub fn name(toml_string: &str, index: usize) -> Option<String> {
+        toml_string.lines()
+            .skip_while({
+                let mut seen = 0;
+
+                move |line| {
+                    if line.trim() == "[[resources]]" {
+                        seen += 1;
+                    }
+
+                    seen < index
+                }
+            })
+            .skip(1)
+            .take_while(|line| !line.trim().starts_with('['))
+            .filter_map(|line| KubeResourceLine::_key_value(line))
+            .find(|(key, _)| *key == "name")
+            .map(|(_, value)| value.to_string())
+    }
+
+    fn _key_value(line: &str) -> Option<(&str, &str)> {
+        let (key, value) = line.split_once('=')?;
+
+        Some((key.trim(), value.trim().trim_matches('"').trim_matches('\'')))
+    }
+}
+
 impl KubeResourceResolve {
     pub fn call(id: &str, resource_path: &str) -> String {
         // e.g. "kubernetes/resources.toml:api-staging"
@@ -92,4 +823,75 @@ impl KubeResourceResolve {
 
         format!("{}/{}", root_dir, resource_vec[0])
     }
+
+    pub fn key(resource_path: &str) -> Option<String> {
+        resource_path.split(":").nth(1).map(|s| s.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // parse a resources file written to a temp dir
+    fn parse(toml_string: &str) -> Result<Vec<KubeResource>, KubeResourceError> {
+        let file = std::env::temp_dir().join(format!("deploybot-test-{}.toml", ulid::Ulid::new()));
+
+        std::fs::write(&file, toml_string).unwrap();
+
+        let resources = KubeResourceParser::new(&file.to_string_lossy().to_string(), &"".to_string()).parse();
+
+        std::fs::remove_file(&file).unwrap();
+
+        resources
+    }
+
+    #[test]
+    fn vars_as_written() {
+        let resources = parse(r#"
+[defaults]
+kube_context = "c"
+vars = { ratio = 0.50 }
+
+[[resources]]
+name = "api"
+docker_file = "Dockerfile"
+image_name = "gcr.io/project/api"
+
+[resources.vars]
+cpu = 1.0
+replicas = 2
+debug = false
+level = "info"
+"#).unwrap();
+
+        let vars = &resources[0].vars;
+
+        assert_eq!(vars["cpu"], "1.0");
+        assert_eq!(vars["ratio"], "0.50");
+        assert_eq!(vars["replicas"], "2");
+        assert_eq!(vars["debug"], "false");
+        assert_eq!(vars["level"], "info");
+    }
+
+    #[test]
+    fn template_strict_default() {
+        let resources = parse(r#"
+[[resources]]
+name = "api"
+docker_file = "Dockerfile"
+image_name = "gcr.io/project/api"
+kube_context = "c"
+
+[[resources]]
+name = "worker"
+docker_file = "Dockerfile"
+image_name = "gcr.io/project/worker"
+kube_context = "c"
+template_strict = false
+"#).unwrap();
+
+        assert!(resources[0].template_strict);
+        assert!(!resources[1].template_strict);
+    }
 }
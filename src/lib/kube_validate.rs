@@ -0,0 +1,319 @@
+use super::kube_image_set::KubeImageSet;
+use super::kube_resource::{KubeImage, KubeResource, KubeResourceError, KubeResourceLine, KubeResourceParser};
+use super::template::{TemplateRender, TEMPLATE_BUILD_BUILTINS, TEMPLATE_BUILTINS};
+
+use std::path::Path;
+
+const IMAGE_TEMPLATE_VAR: &str = "image";
+const IMAGES_TEMPLATE_PREFIX: &str = "images.";
+
+//
+// validate a resources file in a local checkout, e.g. in an app repo's ci:
+//
+// deploybot validate kubernetes/resources.toml
+//
+
+#[derive(Debug)]
+pub struct KubeValidate {
+    pub root_dir: String,
+    pub resource_file: String,
+    pub resource_keys: Vec<String>,  // validate these resources, or all if empty
+    toml_string: String,
+}
+
+impl KubeValidate {
+    pub fn new(root_dir: &str, resource_file: &str, resource_keys: Vec<String>) -> KubeValidate {
+        KubeValidate {
+            root_dir: root_dir.to_string(),
+            resource_file: resource_file.to_string(),
+            resource_keys: resource_keys,
+            toml_string: "".to_string(),
+        }
+    }
+
+    pub fn call(&mut self) -> Vec<KubeResourceError> {
+        let resource_path = format!("{}/{}", self.root_dir, self.resource_file);
+
+        // keep file contents to report resource line numbers
+        self.toml_string = std::fs::read_to_string(&resource_path).unwrap_or("".to_string());
+
+        let resource_parser = KubeResourceParser::new(
+            &resource_path,
+            &"".to_string(),
+        );
+
+        let resources = match resource_parser.parse() {
+            Err(mut e) => {
+                e.file = self.resource_file.to_string();
+
+                return vec![e]
+            },
+            Ok(resources) => {
+                resources
+            }
+        };
+
+        let mut errors = Vec::new();
+
+        for key in self.resource_keys.iter() {
+            if !resources.iter().any(|resource| &resource.name == key) {
+                errors.push(self._error(key, "name", "resource not found"));
+            }
+        }
+
+        for resource in resources.iter() {
+            if !self.resource_keys.is_empty() && !self.resource_keys.contains(&resource.name) {
+                continue
+            }
+
+            errors.extend(self._resource_validate(resource));
+        }
+
+        errors
+    }
+
+    fn _resource_validate(&self, resource: &KubeResource) -> Vec<KubeResourceError> {
+        let mut errors = Vec::new();
+
+        let images = resource.images_list();
+
+        for image in images.iter() {
+            errors.extend(self._image_validate(resource, image));
+        }
+
+        // images replaced by placeholder rather than set by container
+        let images_replace: Vec<&KubeImage> = images.iter().filter(|image| image.image_set.is_none()).collect();
+
+        let mut images_referenced = vec![false; images.len()];
+        let mut image_set_counts = vec![0; images.len()];
+
+        for (field, files) in [("console_files", &resource.console_files), ("resource_files", &resource.resource_files)].iter() {
+            for file in files.iter() {
+                match std::fs::read_to_string(format!("{}/{}", self.root_dir, file)) {
+                    Err(_) => {
+                        errors.push(self._error(&resource.name, field, &format!("file not found: {}", file)));
+                    },
+                    Ok(data) => {
+                        let variables = TemplateRender::variables(&data);
+
+                        for (i, image) in images.iter().enumerate() {
+                            match &image.image_set {
+                                Some(image_set) => {
+                                    match KubeImageSet::new(image_set, "").call(&data) {
+                                        Err(e) => {
+                                            errors.push(self._error(&resource.name, field, &format!("invalid yaml: {}: {}", file, e)));
+                                        },
+                                        Ok((_, count)) => {
+                                            image_set_counts[i] += count;
+                                        }
+                                    };
+                                },
+                                None => {
+                                    if self._image_referenced(image, i == 0, &data, &variables) {
+                                        images_referenced[i] = true;
+                                    }
+                                }
+                            };
+                        }
+
+                        let file_referenced = images_replace.iter().any(|image| {
+                            self._image_referenced(image, image.name == images[0].name, &data, &variables)
+                        });
+
+                        if !images_replace.is_empty() && !file_referenced {
+                            let placeholders: Vec<String> = images_replace.iter().map(|image| image.placeholder.clone().unwrap_or_default()).collect();
+
+                            errors.push(self._error(&resource.name, field, &format!("{} placeholder missing: {}", placeholders.join(" or "), file)));
+                        }
+
+                        for (name, line) in variables.iter() {
+                            if !self._variable_defined(resource, name) && resource.template_strict {
+                                errors.push(self._error(&resource.name, field, &format!("undefined variable '{}': {}:{}", name, file, line)));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for (i, image) in images.iter().enumerate() {
+            let field = if resource.images.is_empty() { "image_set" } else { "images" };
+
+            if image.image_set.is_some() && image_set_counts[i] == 0 {
+                errors.push(self._error(&resource.name, field, &format!("no containers match image '{}'", image.name)));
+            }
+
+            // single image resources are checked per file above
+            if image.image_set.is_none() && !images_referenced[i] && images.len() > 1 {
+                errors.push(self._error(&resource.name, field, &format!("image '{}' is not used in any file", image.name)));
+            }
+        }
+
+        if let Some(kustomize) = &resource.kustomize {
+            if !Path::new(&format!("{}/{}", self.root_dir, kustomize.path)).is_dir() {
+                errors.push(self._error(&resource.name, "kustomize", &format!("path not found: {}", kustomize.path)));
+            }
+        }
+
+        if let Some(helm) = &resource.helm {
+            // chart is a dir in the checkout or an oci:// reference
+            if !helm.chart.contains("://") && !Path::new(&format!("{}/{}", self.root_dir, helm.chart)).is_dir() {
+                errors.push(self._error(&resource.name, "helm", &format!("chart not found: {}", helm.chart)));
+            }
+
+            for file in helm.values_files.iter() {
+                if !self._file_exists(file) {
+                    errors.push(self._error(&resource.name, "helm", &format!("values file not found: {}", file)));
+                }
+            }
+        }
+
+        for (i, watch) in resource.watches.iter().enumerate() {
+            let field = format!("watches[{}]", i);
+
+            if watch.cmd.split_whitespace().next().is_none() {
+                errors.push(self._error(&resource.name, &field, "cmd is empty"));
+            }
+
+            if watch.sleep == 0 {
+                errors.push(self._error(&resource.name, &field, "sleep must be greater than 0"));
+            }
+        }
+
+        errors
+    }
+
+    fn _image_validate(&self, resource: &KubeResource, image: &KubeImage) -> Vec<KubeResourceError> {
+        let mut errors = Vec::new();
+        let field = if resource.images.is_empty() { "docker_file" } else { "images" };
+
+        if !self._file_exists(&image.docker_file) {
+            errors.push(self._error(&resource.name, field, &format!("file not found: {}", image.docker_file)));
+        }
+
+        let build = image.build.clone().unwrap_or_default();
+
+        if let Some(context) = &build.context {
+            if !Path::new(&format!("{}/{}", self.root_dir, context)).is_dir() {
+                errors.push(self._error(&resource.name, "build", &format!("context not found: {}", context)));
+            }
+        }
+
+        // build options are always rendered strictly, before any image exists
+        for (key, value) in build.args.iter().chain(build.labels.iter()) {
+            for (name, _) in TemplateRender::variables(value).iter() {
+                if !self._build_variable_defined(resource, name) {
+                    errors.push(self._error(&resource.name, "build", &format!("undefined variable '{}' in {}", name, key)));
+                }
+            }
+        }
+
+        errors
+    }
+
+    // image placeholder or template variable, e.g. :worker_image or {{ images.worker }}
+    fn _image_referenced(&self, image: &KubeImage, primary: bool, data: &str, variables: &[(String, usize)]) -> bool {
+        let placeholder = image.placeholder.clone().unwrap_or_default();
+        let image_var = format!("{}{}", IMAGES_TEMPLATE_PREFIX, image.name);
+
+        (!placeholder.is_empty() && data.contains(&placeholder)) ||
+            variables.iter().any(|(name, _)| name == &image_var || (primary && name == IMAGE_TEMPLATE_VAR))
+    }
+
+    fn _variable_defined(&self, resource: &KubeResource, name: &str) -> bool {
+        TEMPLATE_BUILTINS.contains(&name) ||
+            name.strip_prefix("vars.").map(|key| resource.vars.contains_key(key)) == Some(true) ||
+            name.strip_prefix(IMAGES_TEMPLATE_PREFIX).map(|key| self._image_variable_defined(resource, key)) == Some(true)
+    }
+
+    fn _build_variable_defined(&self, resource: &KubeResource, name: &str) -> bool {
+        TEMPLATE_BUILD_BUILTINS.contains(&name) ||
+            name.strip_prefix("vars.").map(|key| resource.vars.contains_key(key)) == Some(true)
+    }
+
+    // e.g. images.worker, images.worker.digest
+    fn _image_variable_defined(&self, resource: &KubeResource, key: &str) -> bool {
+        resource.images_list().iter().any(|image| {
+            key == image.name || ["name", "tag", "digest"].iter().any(|suffix| key == format!("{}.{}", image.name, suffix))
+        })
+    }
+
+    fn _file_exists(&self, file: &str) -> bool {
+        Path::new(&format!("{}/{}", self.root_dir, file)).is_file()
+    }
+
+    fn _error(&self, resource: &str, field: &str, message: &str) -> KubeResourceError {
+        let mut error = KubeResourceError::new(&self.resource_file, message);
+        error.resource = Some(resource.to_string());
+        error.field = Some(field.to_string());
+        error.line = KubeResourceLine::call(&self.toml_string, resource, field);
+
+        error
+    }
+}
+
+#[derive(Debug)]
+pub struct KubeValidateCommand {}
+
+impl KubeValidateCommand {
+    //
+    // deploybot validate [--root <dir>] <resources_file> [resource ...]
+    //
+    // prints one line per error and returns the process exit code
+    //
+
+    pub fn call(args: &[String]) -> i32 {
+        let mut root_dir = ".".to_string();
+        let mut positional = Vec::new();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--root" => {
+                    match iter.next() {
+                        Some(value) => {
+                            root_dir = value.to_string();
+                        },
+                        None => {
+                            return KubeValidateCommand::_usage()
+                        }
+                    }
+                },
+                "-h" | "--help" => {
+                    return KubeValidateCommand::_usage()
+                },
+                _ => {
+                    positional.push(arg.to_string());
+                }
+            }
+        }
+
+        if positional.is_empty() {
+            return KubeValidateCommand::_usage()
+        }
+
+        let resource_file = positional.remove(0);
+
+        let errors = KubeValidate::new(&root_dir, &resource_file, positional).call();
+
+        for error in errors.iter() {
+            println!("{}", error);
+        }
+
+        if !errors.is_empty() {
+            println!("{} error(s) found in {}", errors.len(), resource_file);
+
+            return 1
+        }
+
+        println!("{} ok", resource_file);
+
+        0
+    }
+
+    fn _usage() -> i32 {
+        eprintln!("usage: deploybot validate [--root <dir>] <resources_file> [resource ...]");
+
+        2
+    }
+}
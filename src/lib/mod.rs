@@ -1,12 +1,27 @@
+pub mod config;
 pub mod deploy;
+pub mod deploy_record;
 pub mod docker;
 pub mod fs;
 pub mod git;
+pub mod git_auth;
+pub mod git_log;
+pub mod git_repo;
+pub mod git_ref_policy;
+pub mod git_verify;
+pub mod image_builder;
 pub mod kube;
 pub mod kube_files_apply;
+pub mod kube_files_render;
 pub mod kube_files_rewriter;
+pub mod kube_image_set;
 pub mod kube_resource;
+pub mod kube_validate;
 pub mod pki;
+pub mod registry_auth;
 pub mod runner;
+pub mod shutdown;
 pub mod slack;
+pub mod template;
 pub mod watch;
+pub mod workspace;
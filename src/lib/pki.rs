@@ -8,34 +8,32 @@ use slog::{error, info};
 use std::fs;
 use std::io::{Error, ErrorKind};
 
+use super::config::PkiConfig;
+
 #[derive(Debug)]
 pub struct PkiCheck {
     pub id: String,
+    pub config: PkiConfig,
 }
 
 impl PkiCheck {
-    pub fn new(id: &str) -> PkiCheck {
+    pub fn new(id: &str, config: &PkiConfig) -> PkiCheck {
         PkiCheck {
             id: id.to_string(),
+            config: config.clone(),
         }
     }
 
     pub fn call(&self, plaintext_message: &str, crypto_signature: &str, logger: &slog::Logger) -> std::io::Result<()> {
-        let pki_dir = dotenv::var("PKI_DIR_ANY").unwrap();
-        let pki_check = dotenv::var("PKI_CHECK").unwrap_or("1".to_string());
-
-        match pki_check {
-            _ if pki_check == "0" => {
-                info!(logger, "pki_check_ignore"; "id" => &self.id);
+        if !self.config.check {
+            info!(logger, "pki_check_ignore"; "id" => &self.id);
 
-                return Ok(())
-            },
-            _ => {}
-        };
+            return Ok(())
+        }
 
         // pki_check is required
 
-        for entry in fs::read_dir(pki_dir)? {
+        for entry in fs::read_dir(&self.config.dir_any)? {
             let dir = entry?;
             let name = dir.path().to_str().unwrap().to_string();
 
@@ -0,0 +1,258 @@
+use super::config::{Config, RegistryConfig, RegistryHelper};
+use super::fs::FsDockerConfig;
+
+use slog::{error, info};
+use std::fs;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::unix::fs::{symlink, DirBuilderExt, OpenOptionsExt};
+use std::path::Path;
+use std::process::Command;
+
+// fixed usernames for registry access tokens
+const ACR_TOKEN_USERNAME: &str = "00000000-0000-0000-0000-000000000000";
+const ECR_TOKEN_USERNAME: &str = "AWS";
+const GCR_TOKEN_USERNAME: &str = "oauth2accesstoken";
+
+//
+// write a docker config.json with credentials for the configured registries, used by the
+// image builders for this deploy only, credentials are read from files or token helpers and
+// never logged
+//
+
+#[derive(Debug)]
+pub struct RegistryAuth {
+    pub id: String,
+    pub config: Config,
+    pub logger: slog::Logger,
+}
+
+#[derive(Debug)]
+pub struct RegistryAuthRemove {}
+
+impl RegistryAuth {
+    pub fn new(id: &String, config: &Config, logger: slog::Logger) -> RegistryAuth {
+        RegistryAuth {
+            id: id.to_owned(),
+            config: config.clone(),
+            logger: logger,
+        }
+    }
+
+    pub fn call(&self) -> Option<i32> {
+        // no registries, keep using the ambient docker credentials
+        if self.config.docker.registries.is_empty() {
+            return Some(0)
+        }
+
+        let mut auths = serde_json::Map::new();
+
+        for (host, registry) in self.config.docker.registries.iter() {
+            match self._registry_auth(host, registry) {
+                Ok(auth) => {
+                    info!(self.logger, "registry_auth_ok"; "registry" => host);
+
+                    auths.insert(host.to_string(), auth);
+                },
+                Err(e) => {
+                    error!(self.logger, "registry_auth_exception: {}", e; "registry" => host);
+
+                    return Some(500)
+                }
+            };
+        }
+
+        let docker_config = self._ambient_merge(auths);
+
+        match self._write(&docker_config) {
+            Ok(_) => {},
+            Err(e) => {
+                error!(self.logger, "registry_auth_exception: {}", e);
+
+                return Some(500)
+            }
+        };
+
+        Some(0)
+    }
+
+    //
+    // keep the host's docker config, e.g. credHelpers and credsStore for other registries,
+    // configured registries replace its auths and credHelpers entries
+    //
+
+    fn _ambient_merge(&self, auths: serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+        let mut docker_config = match self._ambient_read() {
+            Some(serde_json::Value::Object(map)) => {
+                map
+            },
+            _ => {
+                serde_json::Map::new()
+            }
+        };
+
+        if let Some(cred_helpers) = docker_config.get_mut("credHelpers").and_then(|v| v.as_object_mut()) {
+            for host in auths.keys() {
+                cred_helpers.remove(host);
+            }
+        }
+
+        let mut auths_merged = match docker_config.remove("auths") {
+            Some(serde_json::Value::Object(map)) => {
+                map
+            },
+            _ => {
+                serde_json::Map::new()
+            }
+        };
+
+        auths_merged.extend(auths);
+
+        docker_config.insert("auths".to_string(), serde_json::Value::Object(auths_merged));
+
+        serde_json::Value::Object(docker_config)
+    }
+
+    fn _ambient_read(&self) -> Option<serde_json::Value> {
+        let dir = FsDockerConfig::ambient()?;
+
+        let data = fs::read_to_string(format!("{}/config.json", dir)).ok()?;
+
+        match serde_json::from_str(&data) {
+            Ok(value) => {
+                Some(value)
+            },
+            Err(e) => {
+                error!(self.logger, "registry_auth_exception: {}", e; "file" => format!("{}/config.json", dir));
+
+                None
+            }
+        }
+    }
+
+    fn _registry_auth(&self, host: &str, registry: &RegistryConfig) -> Result<serde_json::Value> {
+        let (username, password) = match &registry.helper {
+            Some(helper) => {
+                self._helper_token(host, registry, helper)?
+            },
+            None if !registry.token_file.is_empty() => {
+                let token = self._file_read(&registry.token_file)?;
+
+                // a token without a username is sent as a bearer token
+                if registry.username.is_empty() {
+                    return Ok(serde_json::json!({ "registrytoken": token }))
+                }
+
+                (registry.username.to_string(), token)
+            },
+            None => {
+                (registry.username.to_string(), self._file_read(&registry.password_file)?)
+            }
+        };
+
+        let auth = openssl::base64::encode_block(format!("{}:{}", username, password).as_bytes());
+
+        Ok(serde_json::json!({ "auth": auth }))
+    }
+
+    //
+    // cloud registry tokens, using the cli credentials of the deploybot host:
+    // gcloud auth print-access-token, aws ecr get-login-password, az acr login --expose-token
+    //
+
+    fn _helper_token(&self, host: &str, registry: &RegistryConfig, helper: &RegistryHelper) -> Result<(String, String)> {
+        match helper {
+            RegistryHelper::Acr => {
+                // e.g. myregistry.azurecr.io
+                let name = host.split('.').next().unwrap_or(host);
+
+                let token = self._command_output("az", &["acr", "login", "--name", name, "--expose-token", "--output", "tsv", "--query", "accessToken"])?;
+
+                Ok((ACR_TOKEN_USERNAME.to_string(), token))
+            },
+            RegistryHelper::Ecr => {
+                // e.g. 123456789012.dkr.ecr.us-east-1.amazonaws.com
+                let region = if registry.region.is_empty() {
+                    host.split('.').nth(3).unwrap_or("").to_string()
+                } else {
+                    registry.region.to_string()
+                };
+
+                if region.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "ecr region is required"))
+                }
+
+                let token = self._command_output("aws", &["ecr", "get-login-password", "--region", &region])?;
+
+                Ok((ECR_TOKEN_USERNAME.to_string(), token))
+            },
+            RegistryHelper::Gcr => {
+                let token = self._command_output("gcloud", &["auth", "print-access-token"])?;
+
+                Ok((GCR_TOKEN_USERNAME.to_string(), token))
+            }
+        }
+    }
+
+    // stdout is the token, only the exit status is reported on failure
+    fn _command_output(&self, cmd: &str, args: &[&str]) -> Result<String> {
+        let output = Command::new(cmd)
+            .args(args)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(Error::new(ErrorKind::Other, format!("{} {} {}", cmd, args[0], output.status)))
+        }
+
+        let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        if token.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} returned no token", cmd)))
+        }
+
+        Ok(token)
+    }
+
+    fn _file_read(&self, file: &str) -> Result<String> {
+        Ok(fs::read_to_string(file)?.trim().to_string())
+    }
+
+    // config.json readable by the deploybot user only
+    fn _write(&self, docker_config: &serde_json::Value) -> Result<()> {
+        let dir = FsDockerConfig::call(&self.id);
+
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(format!("{}/config.json", dir))?;
+
+        file.write_all(serde_json::to_string(docker_config)?.as_bytes())?;
+
+        // the docker cli looks for plugins, e.g. buildx, in DOCKER_CONFIG/cli-plugins
+        if let Some(ambient) = FsDockerConfig::ambient() {
+            let plugins = format!("{}/cli-plugins", ambient);
+
+            if Path::new(&plugins).is_dir() {
+                match symlink(&plugins, format!("{}/cli-plugins", dir)) {
+                    Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+                        return Err(e)
+                    },
+                    _ => {}
+                };
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl RegistryAuthRemove {
+    pub fn call(id: &str) -> Option<i32> {
+        let _ = fs::remove_dir_all(FsDockerConfig::call(id));
+
+        Some(0)
+    }
+}
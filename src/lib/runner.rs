@@ -1,12 +1,23 @@
 use slog::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{thread, time};
 
-use super::docker::DockerStage;
+use super::config::Config;
+use super::deploy_record::{DeployRecord, DeployRecordCommit, DeployRecordImage, DeployRecordLatest, DeployRecordWrite};
+use super::docker::{DockerImage, DockerStage};
+use super::fs::FsRoot;
 use super::git::GitStage;
+use super::git_log::GitLog;
 use super::kube::KubeStage;
+use super::kube_resource::{KubeResource, KubeResourceError, KubeResourceParser, KubeResourceResolve};
 use super::slack::{SlackChatPublish, SlackMessage};
+use super::template::TemplateVars;
 use super::watch::WatchStage;
 
+// changelog commits listed in the slack message, the deploy record has all of them
+const SLACK_CHANGELOG_MAX: usize = 10;
+
 #[derive(Debug)]
 pub struct StageRunner {
     pub id: String,
@@ -14,21 +25,40 @@ pub struct StageRunner {
     pub tag: String,
     pub sha: String,
     pub path: String,
+    pub config: Config,
+    pub record: DeployRecord,
+    pub interrupted: bool,  // stopped by a shutdown before anything was applied
+    pub unverified: bool,  // applied, but a shutdown stopped the watch stage before the rollout finished
+    pub shutdown: Arc<AtomicBool>,
     pub logger: slog::Logger,
     pub slack_channel: crossbeam_channel::Sender<String>,
 }
 
+// handles shared by every deploy of the deploy thread
+#[derive(Clone, Debug)]
+pub struct StageRunnerHandles {
+    pub shutdown: Arc<AtomicBool>,
+    pub slack_channel: crossbeam_channel::Sender<String>,
+}
+
 impl StageRunner {
 
-    pub fn new(id: String, repo: String, tag: String, path: String, logger: slog::Logger, slack_channel: crossbeam_channel::Sender<String>) -> StageRunner {
+    pub fn new(id: String, repo: String, tag: String, path: String, config: Config, handles: StageRunnerHandles, logger: slog::Logger) -> StageRunner {
+        let record = DeployRecord::new(&id, &repo, &tag, &path);
+
         StageRunner {
             id: id,
             repo: repo,
             tag: tag,
             sha: "".to_string(),
             path: path,
+            config: config,
+            record: record,
+            interrupted: false,
+            unverified: false,
+            shutdown: handles.shutdown,
             logger: logger,
-            slack_channel: slack_channel,
+            slack_channel: handles.slack_channel,
         }
     }
 
@@ -36,12 +66,22 @@ impl StageRunner {
     // run the deploy stages:
     // git, docker, kube, watch
     //
+    // a shutdown stops the deploy before the docker and kube stages, once manifests are applied
+    // the deploy runs to completion without waiting on the watch stage, and is unverified
+    //
 
     pub fn call(&mut self) -> Option<i32> {
+        self._record_write();
+
+        // last completed deploy of the resource, for the changelog and build cache
+        let previous = DeployRecordLatest::call(&self.repo, &self.path).unwrap_or_default();
+
         let mut git_stage = GitStage::new(
             &self.id,
             &self.repo,
             &self.tag,
+            &self.path,
+            &self.config,
             self.logger.clone(),
         );
 
@@ -54,12 +94,19 @@ impl StageRunner {
                 info!(self.logger, "git_stage_completed"; "id" => &self.id);
 
                 // update git sha
-                self.sha = git_stage.sha
+                self.sha = git_stage.sha;
+                self.record.sha = self.sha.to_string();
+
+                self._git_log(&previous.sha);
+
+                self._record_write();
+
+                self._slack_message_detail("git_stage_completed", "pending", &self._changelog_detail());
             },
             Some(code) => {
                 info!(self.logger, "git_stage_exception"; "code" => code, "id" => &self.id);
 
-                self._slack_message("git_stage_exception", "error");
+                self._slack_message_detail("git_stage_exception", "error", git_stage.error.as_ref().unwrap_or(&"".to_string()));
 
                 return Some(code)
             },
@@ -72,9 +119,40 @@ impl StageRunner {
             }
         };
 
+        let resource = match self._resource_parse() {
+            Ok(resource) => {
+                info!(self.logger, "resource_parse_completed"; "resource" => &resource.name, "id" => &self.id);
+
+                resource
+            },
+            Err(e) => {
+                error!(self.logger, "resource_parse_exception: {}", e.message;
+                    "file" => &self.path,
+                    "resource" => e.resource.as_ref().unwrap_or(&"".to_string()),
+                    "field" => e.field.as_ref().unwrap_or(&"".to_string()),
+                    "line" => e.line.unwrap_or(0),
+                    "id" => &self.id,
+                );
+
+                self._slack_message_detail("resource_parse_exception", "error", &e.to_string());
+
+                return Some(400)
+            }
+        };
+
+        if self._interrupt("docker_stage") {
+            return Some(503)
+        }
+
+        // images of the last completed deploy, a build cache for resources that enable it
+        let previous_images = previous.images;
+
         let mut docker_stage = DockerStage::new(
             &self.id,
-            &self.path,
+            &resource,
+            &self._template_vars(&resource),
+            &previous_images,
+            &self.config,
             self.logger.clone(),
         );
 
@@ -82,9 +160,39 @@ impl StageRunner {
 
         self._slack_message("docker_stage_starting", "pending");
 
+        let docker_started = std::time::Instant::now();
+
         match docker_stage.call() {
             Some(0) => {
                 info!(self.logger, "docker_stage_completed"; "id" => &self.id);
+
+                // primary image
+                if let Some(image) = docker_stage.images.first() {
+                    self.record.image = image.image_tag.to_string();
+                    self.record.image_digest = image.image_digest.to_string();
+                    self.record.image_reused = image.image_reused;
+                }
+
+                self.record.images = docker_stage.images.iter().map(|image| DeployRecordImage {
+                    name: image.name.to_string(),
+                    image: image.image_tag.to_string(),
+                    image_digest: image.image_digest.to_string(),
+                    image_reused: image.image_reused,
+                    build_seconds: image.build_seconds,
+                    build_steps: image.build_stats.steps,
+                    build_cached: image.build_stats.cached,
+                }).collect();
+
+                self.record.build_seconds = docker_started.elapsed().as_secs();
+
+                let build_steps: u32 = docker_stage.images.iter().map(|image| image.build_stats.steps).sum();
+                let build_cached: u32 = docker_stage.images.iter().map(|image| image.build_stats.cached).sum();
+
+                if build_steps > 0 {
+                    self.record.cache_hit_rate = build_cached as f64 / build_steps as f64;
+                }
+
+                self._record_write();
             },
             Some(code) => {
                 info!(self.logger, "docker_stage_exception"; "code" => code, "id" => &self.id);
@@ -102,10 +210,15 @@ impl StageRunner {
             }
         };
 
+        if self._interrupt("kube_stage") {
+            return Some(503)
+        }
+
         let kube_stage = KubeStage::new(
             &self.id,
-            &self.path,
-            &docker_stage.image_tag,
+            &resource,
+            &self._template_vars_image(&resource, &docker_stage.images),
+            &docker_stage.images,
             self.logger.clone(),
         );
 
@@ -141,7 +254,7 @@ impl StageRunner {
 
         let watch_stage = WatchStage::new(
             &self.id,
-            &self.path,
+            &resource,
             self.logger.clone(),
         );
 
@@ -157,7 +270,21 @@ impl StageRunner {
         let mut waited = 0;
 
         for watch_object in watch_objects.iter() {
+            if self.unverified {
+                break;
+            }
+
             loop {
+                if self.shutdown.load(Ordering::SeqCst) {
+                    info!(self.logger, "watch_stage_interrupted"; "id" => &self.id);
+
+                    self._slack_message_detail("watch_stage_interrupted", "error", "deploybot is shutting down, the rollout was applied but not verified");
+
+                    self.unverified = true;
+
+                    break;
+                }
+
                 match watch_object.call() {
                     Ok(0) => {
                         info!(self.logger, "watch_stage_completed"; "id" => &self.id);
@@ -193,7 +320,202 @@ impl StageRunner {
         Some(0)
     }
 
+    // deployed commit author and message, and the commits since the previous deploy
+    fn _git_log(&mut self, previous_sha: &String) {
+        let git_log = GitLog::new(&self.id, &self.sha, previous_sha);
+
+        match git_log.commit() {
+            Ok((commit, message)) => {
+                self.record.author = commit.author;
+                self.record.message = message;
+            },
+            Err(e) => {
+                warn!(self.logger, "git_log_exception: {}", e; "id" => &self.id);
+            }
+        };
+
+        match git_log.call() {
+            Ok((changelog, truncated, rollback)) => {
+                info!(self.logger, "git_changelog"; "previous_sha" => previous_sha, "commits" => changelog.len(), "rollback" => rollback, "id" => &self.id);
+
+                self.record.previous_sha = previous_sha.to_string();
+                self.record.changelog = changelog;
+                self.record.changelog_truncated = truncated;
+                self.record.rollback = rollback;
+            },
+            Err(e) => {
+                // a changelog is informational, deploy without it
+                warn!(self.logger, "git_changelog_exception: {}", e; "id" => &self.id);
+
+                self.record.changelog_error = e.message().to_string();
+            }
+        };
+    }
+
+    //
+    // compact changelog for slack, e.g.
+    // 3 commits since 1a2b3c4
+    // 5d6e7f8 fix worker retries (Jane Doe)
+    //
+
+    fn _changelog_detail(&self) -> String {
+        if !self.record.changelog_error.is_empty() {
+            return format!("changelog unavailable: {}", self.record.changelog_error)
+        }
+
+        if self.record.previous_sha.is_empty() {
+            return "first deploy of this resource".to_string()
+        }
+
+        let previous_short = &self.record.previous_sha[..std::cmp::min(7, self.record.previous_sha.len())];
+
+        if self.record.previous_sha == self.sha {
+            return format!("redeploy of {}", previous_short)
+        }
+
+        let count = if self.record.changelog_truncated {
+            format!("{}+", self.record.changelog.len())
+        } else {
+            self.record.changelog.len().to_string()
+        };
+
+        let mut lines = if self.record.rollback {
+            vec![format!("rollback from {}, {} commits rolled back", previous_short, count)]
+        } else {
+            vec![format!("{} commits since {}", count, previous_short)]
+        };
+
+        lines.extend(self.record.changelog.iter().take(SLACK_CHANGELOG_MAX).map(StageRunner::_changelog_line));
+
+        if self.record.changelog.len() > SLACK_CHANGELOG_MAX {
+            lines.push(format!("... and {} more", self.record.changelog.len() - SLACK_CHANGELOG_MAX));
+        }
+
+        lines.join("\n")
+    }
+
+    fn _changelog_line(commit: &DeployRecordCommit) -> String {
+        let name = commit.author.split(" <").next().unwrap_or("");
+
+        format!("{} {} ({})", &commit.sha[..std::cmp::min(7, commit.sha.len())], commit.summary, name)
+    }
+
+    // resolve and parse the resource from the checked out resources file, e.g. "kubernetes/resources.toml:api-staging"
+    fn _resource_parse(&self) -> std::result::Result<KubeResource, KubeResourceError> {
+        let resource_file = KubeResourceResolve::call(&self.id, &self.path);
+
+        let resource_key = match KubeResourceResolve::key(&self.path) {
+            None => {
+                return Err(KubeResourceError::new(&self.path, "resource path must be 'file:resource'"))
+            },
+            Some(key) => {
+                key
+            }
+        };
+
+        let mut result = KubeResourceParser::new(&resource_file, &resource_key).call();
+
+        // report file relative to the checkout
+        if let Err(e) = result.as_mut() {
+            e.file = e.file.replacen(&format!("{}/", FsRoot::call(&self.id)), "", 1);
+        }
+
+        result
+    }
+
+    //
+    // template variables, e.g. {{ git_sha }}, {{ vars.replicas }}
+    //
+
+    fn _template_vars(&self, resource: &KubeResource) -> TemplateVars {
+        let mut vars = TemplateVars::new();
+
+        vars.insert("git_repo".to_string(), self.repo.to_string());
+        vars.insert("git_sha".to_string(), self.sha.to_string());
+        vars.insert("git_tag".to_string(), self.tag.to_string());
+        vars.insert("deploy_id".to_string(), self.id.to_string());
+        vars.insert("resource_name".to_string(), resource.name.to_string());
+
+        for (key, value) in resource.vars.iter() {
+            vars.insert(format!("vars.{}", key), value.to_string());
+        }
+
+        vars
+    }
+
+    // manifest template variables, adds the images that were built, e.g. {{ image }}, {{ images.worker }}
+    fn _template_vars_image(&self, resource: &KubeResource, images: &[DockerImage]) -> TemplateVars {
+        let mut vars = self._template_vars(resource);
+
+        for (i, image) in images.iter().enumerate() {
+            // pin to the digest that was pushed when known
+            let image_ref = image.reference(resource.pin_digest);
+
+            // primary image
+            if i == 0 {
+                vars.insert("image".to_string(), image_ref.to_string());
+                vars.insert("image_name".to_string(), image.image_name.to_string());
+                vars.insert("image_tag".to_string(), image.version().to_string());
+
+                if !image.image_digest.is_empty() {
+                    vars.insert("image_digest".to_string(), image.image_digest.to_string());
+                }
+            }
+
+            vars.insert(format!("images.{}", image.name), image_ref);
+            vars.insert(format!("images.{}.name", image.name), image.image_name.to_string());
+            vars.insert(format!("images.{}.tag", image.name), image.version().to_string());
+
+            if !image.image_digest.is_empty() {
+                vars.insert(format!("images.{}.digest", image.name), image.image_digest.to_string());
+            }
+        }
+
+        vars
+    }
+
+    // record the deploy result
+    pub fn finish(&mut self, code: i32) {
+        if self.interrupted {
+            self.record.interrupt();
+        } else if self.unverified && code == 0 {
+            self.record.unverify();
+        } else {
+            self.record.finish(code);
+        }
+
+        self._record_write();
+    }
+
+    // safe point before a stage, nothing has been applied yet
+    fn _interrupt(&mut self, stage: &str) -> bool {
+        if !self.shutdown.load(Ordering::SeqCst) {
+            return false
+        }
+
+        info!(self.logger, "deploy_interrupted"; "stage" => stage, "id" => &self.id);
+
+        self._slack_message_detail("deploy_interrupted", "error", &format!("deploybot is shutting down, stopped before {}, the deploy is retried on restart", stage));
+
+        self.interrupted = true;
+
+        true
+    }
+
+    fn _record_write(&self) {
+        match DeployRecordWrite::call(&self.record) {
+            Err(e) => {
+                error!(self.logger, "deploy_record_exception: {}", e; "id" => &self.id);
+            },
+            Ok(_) => {}
+        };
+    }
+
     fn _slack_message(&self, subject: &str, state: &str) -> Option<i32> {
+        self._slack_message_detail(subject, state, "")
+    }
+
+    fn _slack_message_detail(&self, subject: &str, state: &str, detail: &str) -> Option<i32> {
         let message = SlackMessage {
             subject: subject.to_string(),
             state: state.to_string(),
@@ -202,6 +524,9 @@ impl StageRunner {
             git_repo: self.repo.to_string(),
             git_tag: self.tag.to_string(),
             git_sha: self.sha.to_string(),
+            git_author: self.record.author.to_string(),
+            git_message: self.record.message.lines().next().unwrap_or("").to_string(),
+            detail: detail.to_string(),
         };
 
         match SlackChatPublish::call(&self.slack_channel, &message) {
@@ -0,0 +1,162 @@
+use actix_web::dev::ServerHandle;
+use slog::{error, info, warn};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::config::Config;
+use super::deploy::{DeployMessage, DeploySpool};
+use super::slack::{SlackSpool, SLACK_THREAD_FLUSH, SLACK_THREAD_SPOOL};
+
+// slack messages are delivered with retries, give them this long once deploys have stopped,
+// and again for the current delivery once the rest are spooled
+const SLACK_FLUSH_WAIT: Duration = Duration::from_secs(30);
+
+const THREAD_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+//
+// graceful shutdown on SIGTERM or SIGINT, the deploy api returns 503 once the flag is set,
+// the running deploy finishes or stops at a safe point within the grace period, then slack
+// messages are flushed and queued deploys are spooled for the next start, a second signal
+// exits immediately
+//
+
+#[derive(Debug)]
+pub struct ShutdownSignals {}
+
+#[derive(Debug)]
+pub struct ShutdownDrain {
+    pub config: Config,
+    pub logger: slog::Logger,
+}
+
+// the server and threads stopped by a drain, with the channels left to spool
+#[derive(Debug)]
+pub struct ShutdownHandles {
+    pub server: ServerHandle,
+    pub deploy_thread: thread::JoinHandle<()>,
+    pub deploy_channel: crossbeam_channel::Receiver<String>,
+    pub slack_thread: thread::JoinHandle<()>,
+    pub slack_channel: crossbeam_channel::Receiver<String>,
+    pub slack_state: Arc<AtomicU8>,
+}
+
+impl ShutdownSignals {
+    pub fn call(shutdown: &Arc<AtomicBool>) -> std::io::Result<()> {
+        for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT].iter() {
+            // exits with status 1 if the flag is already set, i.e. on the second signal
+            signal_hook::flag::register_conditional_shutdown(*signal, 1, Arc::clone(shutdown))?;
+            signal_hook::flag::register(*signal, Arc::clone(shutdown))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ShutdownDrain {
+    pub fn new(config: &Config, logger: slog::Logger) -> ShutdownDrain {
+        ShutdownDrain {
+            config: config.clone(),
+            logger: logger,
+        }
+    }
+
+    //
+    // blocks until the shutdown flag is set, then waits for the running deploy, stops the server
+    // so in flight requests have queued their deploys before they are spooled, and flushes slack
+    //
+
+    pub fn call(&self, shutdown: Arc<AtomicBool>, handles: ShutdownHandles) {
+        let ShutdownHandles { server, deploy_thread, deploy_channel, slack_thread, slack_channel, slack_state } = handles;
+
+        while !shutdown.load(Ordering::SeqCst) {
+            thread::sleep(THREAD_POLL_INTERVAL);
+        }
+
+        info!(self.logger, "shutdown_starting"; "grace_secs" => self.config.shutdown.grace_secs);
+
+        let grace = Duration::from_secs(self.config.shutdown.grace_secs);
+
+        if self._thread_wait(&deploy_thread, grace) {
+            info!(self.logger, "shutdown_deploys_stopped");
+        } else {
+            warn!(self.logger, "shutdown_grace_expired"; "grace_secs" => self.config.shutdown.grace_secs);
+        }
+
+        actix_rt::System::new().block_on(server.stop(true));
+
+        self._deploys_spool(&deploy_channel);
+
+        // the deploy thread may still hold a sender, so the slack thread stops once its channel
+        // is empty rather than when every sender is dropped
+        slack_state.store(SLACK_THREAD_FLUSH, Ordering::SeqCst);
+
+        if self._thread_wait(&slack_thread, SLACK_FLUSH_WAIT) {
+            info!(self.logger, "shutdown_slack_flushed");
+        } else {
+            // spooled by the slack thread after its current delivery
+            slack_state.store(SLACK_THREAD_SPOOL, Ordering::SeqCst);
+
+            if !self._thread_wait(&slack_thread, SLACK_FLUSH_WAIT) {
+                warn!(self.logger, "shutdown_slack_abandoned"; "messages" => slack_channel.len());
+
+                return
+            }
+        }
+
+        // sent by a deploy still running after the slack thread stopped
+        self._slack_spool(&slack_channel);
+
+        info!(self.logger, "shutdown_completed");
+    }
+
+    fn _thread_wait(&self, handle: &thread::JoinHandle<()>, wait: Duration) -> bool {
+        let started = Instant::now();
+
+        while !handle.is_finished() {
+            if started.elapsed() > wait {
+                return false
+            }
+
+            thread::sleep(THREAD_POLL_INTERVAL);
+        }
+
+        true
+    }
+
+    // accepted deploys that never started
+    fn _deploys_spool(&self, deploy_channel: &crossbeam_channel::Receiver<String>) {
+        let spool = DeploySpool::new();
+
+        for data in deploy_channel.try_iter() {
+            let id = serde_json::from_str::<DeployMessage>(&data).map(|message| message.id).unwrap_or_default();
+
+            match spool.write(&id, &data) {
+                Ok(path) => {
+                    info!(self.logger, "deploy_spool_write"; "path" => path, "id" => &id);
+                },
+                Err(e) => {
+                    error!(self.logger, "deploy_spool_exception: {}", e; "id" => &id);
+                }
+            };
+        }
+    }
+
+    // undelivered messages are sent by the slack thread on the next start, only read once the
+    // slack thread has stopped
+    fn _slack_spool(&self, slack_channel: &crossbeam_channel::Receiver<String>) {
+        let spool = SlackSpool::new(&self.config.slack.spool_dir);
+
+        for data in slack_channel.try_iter() {
+            match spool.write(&data) {
+                Ok(path) => {
+                    info!(self.logger, "slack_spool_write"; "path" => path);
+                },
+                Err(e) => {
+                    error!(self.logger, "slack_spool_exception: {}", e);
+                }
+            };
+        }
+    }
+}
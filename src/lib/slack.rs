@@ -1,7 +1,46 @@
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use slog::{error, info};
+use slog::{error, info, warn};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::{thread, time};
+use ulid::Ulid;
+
+use super::config::SlackConfig;
+
+const SLACK_DELIVER_ATTEMPTS: u32 = 5;
+const SLACK_DELIVER_BACKOFF_SECS: u64 = 1;
+const SLACK_DELIVER_BACKOFF_MAX_SECS: u64 = 60;
+
+// a channel that stays rate limited is given up on like a transient error, retry-after 0 still waits
+const SLACK_RATE_LIMIT_ATTEMPTS: u32 = 10;
+const SLACK_RATE_LIMIT_MIN_SECS: u64 = 1;
+
+// spooled messages are retried on this interval, and dead lettered once they are this old
+const SLACK_SPOOL_REPLAY_SECS: u64 = 60;
+const SLACK_SPOOL_MAX_AGE_SECS: u64 = 86400;
+
+// slack thread state set by the shutdown, flush stops once the channel is empty, spool stops
+// after the current delivery and spools what's left
+pub const SLACK_THREAD_RUN: u8 = 0;
+pub const SLACK_THREAD_FLUSH: u8 = 1;
+pub const SLACK_THREAD_SPOOL: u8 = 2;
+
+// how often an idle slack thread checks its state
+const SLACK_STATE_POLL_MILLIS: u64 = 200;
+
+// slack api errors that are worth retrying, all others are permanent
+const SLACK_TRANSIENT_ERRORS: [&str; 5] = [
+    "fatal_error",
+    "internal_error",
+    "ratelimited",
+    "request_timeout",
+    "service_unavailable",
+];
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SlackChatAttachment {
@@ -14,6 +53,22 @@ pub struct SlackChatAttachment {
 #[derive(Debug)]
 pub struct SlackChatPost {}
 
+#[derive(Debug, Deserialize)]
+pub struct SlackChatResponse {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SlackChatError {
+    RateLimited(u64),  // seconds to wait before retrying
+    Transient(String),
+    Permanent(String),
+}
+
+#[derive(Debug)]
+pub struct SlackDeliver {}
+
 #[derive(Debug)]
 pub struct SlackChatPublish {}
 
@@ -26,45 +81,79 @@ pub struct SlackMessage {
     pub git_repo: String,
     pub git_tag: String,
     pub git_sha: String,
+    #[serde(default)]
+    pub git_author: String,
+    #[serde(default)]
+    pub git_message: String,  // first line of the commit message
+    #[serde(default)]
+    pub detail: String,  // e.g. error details
+}
+
+#[derive(Debug)]
+pub struct SlackSpool {
+    pub dir: String,
 }
 
 #[derive(Debug)]
 pub struct SlackThread {
     channel: crossbeam_channel::Receiver<String>,
+    state: Arc<AtomicU8>,
+    config: SlackConfig,
     logger: slog::Logger,
 }
 
+impl fmt::Display for SlackChatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SlackChatError::RateLimited(secs) => write!(f, "rate limited, retry after {}s", secs),
+            SlackChatError::Transient(s) => write!(f, "transient error: {}", s),
+            SlackChatError::Permanent(s) => write!(f, "permanent error: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for SlackChatError {}
+
 impl SlackChatPost {
-    pub fn call(message: &SlackMessage) -> Result<(), Box<dyn std::error::Error>> {
-        let api_token = dotenv::var("SLACK_API_TOKEN").unwrap();
-        let channel_name = dotenv::var("SLACK_CHANNEL_NAME").unwrap();
-        let username = dotenv::var("SLACK_USERNAME").unwrap();
+    pub fn call(message: &SlackMessage, config: &SlackConfig) -> Result<(), SlackChatError> {
+        let api_token = &config.api_token;
+        let channel_name = &config.channel_name;
+        let username = &config.username;
 
         let pretext = format!("*{} : {}*", username, message.id);
         let title = message.subject.to_string();
 
         let color = match message.state.as_str() {
             "error" => {
-                dotenv::var("SLACK_COLOR_ERROR").unwrap()
+                config.color_error.clone()
             },
             "pending" => {
-                dotenv::var("SLACK_COLOR_PENDING").unwrap()
+                config.color_pending.clone()
             },
             "success" => {
-                dotenv::var("SLACK_COLOR_SUCCESS").unwrap()
+                config.color_success.clone()
             },
             _ => {
-                dotenv::var("SLACK_COLOR_PENDING").unwrap()
+                config.color_pending.clone()
             }
         };
 
-        let text_vec: [String; 4] = [
+        let mut text_vec = vec![
             format!("resource: {}", message.resource),
             format!("git_repo: {}", message.git_repo),
             format!("git_tag: {}", message.git_tag),
             format!("git_sha: {}", message.git_sha),
         ];
 
+        if !message.git_author.is_empty() {
+            text_vec.push(format!("git_author: {}", message.git_author));
+            text_vec.push(format!("git_message: {}", message.git_message));
+        }
+
+        if !message.detail.is_empty() {
+            text_vec.push(format!("detail: {}", message.detail));
+        }
+
         let text_lines = text_vec.join("\n");
 
         let attachments = vec![
@@ -85,17 +174,213 @@ impl SlackChatPost {
 
         let client = reqwest::blocking::Client::new();
 
-        let _result = client.post("https://slack.com/api/chat.postMessage")
+        let response = match client.post("https://slack.com/api/chat.postMessage")
             .header(AUTHORIZATION, format!("Bearer {}", api_token))
             .header(CONTENT_TYPE, "application/json")
             .body(slack_chat_json)
-            .send()?;
+            .send() {
+            Err(e) => {
+                // connect errors and timeouts
+                return Err(SlackChatError::Transient(e.to_string()))
+            },
+            Ok(response) => {
+                response
+            }
+        };
+
+        let status = response.status();
+
+        let retry_after = response.headers().get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        let body = match response.text() {
+            Err(e) => {
+                return Err(SlackChatError::Transient(e.to_string()))
+            },
+            Ok(body) => {
+                body
+            }
+        };
+
+        SlackChatPost::result(status, retry_after.as_deref(), &body)
+    }
+
+    // classify a chat.postMessage response as delivered, rate limited, transient or permanent
+    pub fn result(status: reqwest::StatusCode, retry_after: Option<&str>, body: &str) -> Result<(), SlackChatError> {
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = retry_after
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .unwrap_or(SLACK_DELIVER_BACKOFF_SECS);
+
+            return Err(SlackChatError::RateLimited(retry_after))
+        }
+
+        if status.is_server_error() {
+            return Err(SlackChatError::Transient(status.to_string()))
+        }
+
+        if !status.is_success() {
+            return Err(SlackChatError::Permanent(status.to_string()))
+        }
+
+        // slack returns 200 with 'ok: false' on api errors
+        let result: SlackChatResponse = match serde_json::from_str(body) {
+            Err(e) => {
+                return Err(SlackChatError::Transient(e.to_string()))
+            },
+            Ok(result) => {
+                result
+            }
+        };
+
+        if result.ok {
+            return Ok(())
+        }
+
+        let error = result.error.unwrap_or("unknown_error".to_string());
+
+        if SLACK_TRANSIENT_ERRORS.contains(&error.as_str()) {
+            return Err(SlackChatError::Transient(error))
+        }
+
+        Err(SlackChatError::Permanent(error))
+    }
+
+}
+
+impl SlackDeliver {
+    //
+    // post message, retrying transient errors with exponential backoff
+    // and waiting out rate limits, until the shutdown wants the queue spooled
+    //
+
+    pub fn call(message: &SlackMessage, config: &SlackConfig, state: &AtomicU8, logger: &slog::Logger) -> Result<(), SlackChatError> {
+        let mut backoff = SLACK_DELIVER_BACKOFF_SECS;
+        let mut attempt = 1;
+        let mut rate_limited = 0;
+
+        loop {
+            let wait = match SlackChatPost::call(message, config) {
+                Ok(_) => {
+                    return Ok(())
+                },
+                Err(SlackChatError::Permanent(s)) => {
+                    return Err(SlackChatError::Permanent(s))
+                },
+                Err(SlackChatError::RateLimited(secs)) => {
+                    rate_limited += 1;
+
+                    if rate_limited >= SLACK_RATE_LIMIT_ATTEMPTS {
+                        return Err(SlackChatError::RateLimited(secs))
+                    }
+
+                    let secs = std::cmp::max(secs, SLACK_RATE_LIMIT_MIN_SECS);
+
+                    warn!(logger, "slack_deliver_rate_limited"; "retry_after" => secs, "id" => &message.id);
+
+                    // rate limits tell us exactly how long to wait, and don't count as an attempt
+                    SlackDeliver::sleep(secs, state)?;
+
+                    continue
+                },
+                Err(e) => {
+                    if attempt >= SLACK_DELIVER_ATTEMPTS {
+                        return Err(e)
+                    }
+
+                    warn!(logger, "slack_deliver_retry: {}", e; "attempt" => attempt, "backoff" => backoff, "id" => &message.id);
+
+                    backoff
+                }
+            };
+
+            SlackDeliver::sleep(wait, state)?;
+
+            backoff = std::cmp::min(backoff * 2, SLACK_DELIVER_BACKOFF_MAX_SECS);
+            attempt += 1;
+        }
+    }
+
+    // a transient error once the slack thread is told to spool, the message is spooled
+    fn sleep(secs: u64, state: &AtomicU8) -> Result<(), SlackChatError> {
+        let until = time::Instant::now() + time::Duration::from_secs(secs);
 
-        // println!("result: {:?}", result);
+        while time::Instant::now() < until {
+            if state.load(Ordering::SeqCst) == SLACK_THREAD_SPOOL {
+                return Err(SlackChatError::Transient("shutting down".to_string()))
+            }
+
+            thread::sleep(until.saturating_duration_since(time::Instant::now()).min(time::Duration::from_millis(SLACK_STATE_POLL_MILLIS)));
+        }
 
         Ok(())
     }
+}
+
+impl SlackSpool {
+    pub fn new(dir: &str) -> SlackSpool {
+        SlackSpool {
+            dir: dir.to_string(),
+        }
+    }
+
+    // write undelivered message to the spool dir, file names are ulids so they sort in send order
+    pub fn write(&self, data: &str) -> std::io::Result<String> {
+        fs::create_dir_all(&self.dir)?;
+
+        let path = format!("{}/{}.json", self.dir, Ulid::new().to_string());
+
+        fs::write(&path, data)?;
+
+        Ok(path)
+    }
+
+    // move a message that can never be delivered out of the spool, e.g. after invalid_auth
+    pub fn dead_letter(&self, path: &str) -> std::io::Result<String> {
+        let dir = self._dead_dir()?;
+
+        let name = Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let dead_path = format!("{}/{}", dir, name);
+
+        fs::rename(path, &dead_path)?;
+
+        Ok(dead_path)
+    }
+
+    // write a message that failed permanently before it was ever spooled
+    pub fn dead_write(&self, data: &str) -> std::io::Result<String> {
+        let path = format!("{}/{}.json", self._dead_dir()?, Ulid::new().to_string());
 
+        fs::write(&path, data)?;
+
+        Ok(path)
+    }
+
+    fn _dead_dir(&self) -> std::io::Result<String> {
+        let dir = format!("{}/dead", self.dir);
+
+        fs::create_dir_all(&dir)?;
+
+        Ok(dir)
+    }
+
+    // list spooled message files, oldest first
+    pub fn list(&self) -> std::io::Result<Vec<String>> {
+        if !Path::new(&self.dir).exists() {
+            return Ok(Vec::new())
+        }
+
+        let mut files: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().to_str().unwrap().to_string())
+            .filter(|path| path.ends_with(".json"))
+            .collect();
+
+        files.sort();
+
+        Ok(files)
+    }
 }
 
 impl SlackChatPublish {
@@ -110,9 +395,11 @@ impl SlackChatPublish {
 
 impl SlackThread {
 
-    pub fn new(channel: crossbeam_channel::Receiver<String>, logger: slog::Logger) -> SlackThread {
+    pub fn new(channel: crossbeam_channel::Receiver<String>, state: Arc<AtomicU8>, config: SlackConfig, logger: slog::Logger) -> SlackThread {
         SlackThread {
             channel: channel,
+            state: state,
+            config: config,
             logger: logger,
         }
     }
@@ -120,10 +407,46 @@ impl SlackThread {
     pub fn call(&self) {
         info!(self.logger, "slack_thread_starting");
 
+        let spool = SlackSpool::new(&self.config.spool_dir);
+        let replay_interval = time::Duration::from_secs(SLACK_SPOOL_REPLAY_SECS);
+
+        self._spool_replay(&spool);
+
+        let mut replayed = time::Instant::now();
+
+        let state_poll = time::Duration::from_millis(SLACK_STATE_POLL_MILLIS);
+
         loop {
-            let data = match self.channel.recv() {
-                Err(e) => {
-                    error!(self.logger, "slack_thread_exception: {}", e);
+            if self.state.load(Ordering::SeqCst) == SLACK_THREAD_SPOOL {
+                self._channel_spool(&spool);
+
+                info!(self.logger, "slack_thread_stopping");
+
+                return ()
+            }
+
+            let timeout = replay_interval.saturating_sub(replayed.elapsed()).min(state_poll);
+
+            let data = match self.channel.recv_timeout(timeout) {
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    // flushed, e.g. a deploy thread past the shutdown grace period holds a sender
+                    if self.state.load(Ordering::SeqCst) == SLACK_THREAD_FLUSH {
+                        info!(self.logger, "slack_thread_stopping");
+
+                        return ()
+                    }
+
+                    if replayed.elapsed() >= replay_interval {
+                        self._spool_replay(&spool);
+
+                        replayed = time::Instant::now();
+                    }
+
+                    continue
+                },
+                Err(_) => {
+                    // every sender was dropped, e.g. on shutdown
+                    info!(self.logger, "slack_thread_stopping");
 
                     return ()
                 },
@@ -144,10 +467,33 @@ impl SlackThread {
                 }
             };
 
-            match SlackChatPost::call(&message) {
+            match SlackDeliver::call(&message, &self.config, &self.state, &self.logger) {
                 Ok(_) => {},
+                Err(SlackChatError::Permanent(e)) => {
+                    // e.g. invalid_auth or channel_not_found, retrying won't help, kept for a resend
+                    error!(self.logger, "slack_thread_dead_letter: {}", e; "id" => &message.id);
+
+                    match spool.dead_write(&data) {
+                        Ok(path) => {
+                            info!(self.logger, "slack_spool_dead_letter"; "path" => path, "id" => &message.id);
+                        },
+                        Err(e) => {
+                            error!(self.logger, "slack_spool_exception: {}", e; "id" => &message.id);
+                        }
+                    };
+                },
                 Err(e) => {
-                    error!(self.logger, "slack_thread_exception: {}", e);
+                    error!(self.logger, "slack_thread_exception: {}", e; "id" => &message.id);
+
+                    // keep message so its sent by a later replay
+                    match spool.write(&data) {
+                        Ok(path) => {
+                            info!(self.logger, "slack_spool_write"; "path" => path, "id" => &message.id);
+                        },
+                        Err(e) => {
+                            error!(self.logger, "slack_spool_exception: {}", e; "id" => &message.id);
+                        }
+                    };
                 }
             }
 
@@ -155,4 +501,192 @@ impl SlackThread {
         }
     }
 
+    // undelivered messages, sent by a replay on the next start
+    fn _channel_spool(&self, spool: &SlackSpool) {
+        for data in self.channel.try_iter() {
+            match spool.write(&data) {
+                Ok(path) => {
+                    info!(self.logger, "slack_spool_write"; "path" => path);
+                },
+                Err(e) => {
+                    error!(self.logger, "slack_spool_exception: {}", e);
+                }
+            };
+        }
+    }
+
+    //
+    // send spooled messages, on startup and then periodically, removing each one that is
+    // delivered, messages that fail permanently or are too old are dead lettered, a transient
+    // failure ends the replay until the next interval
+    //
+
+    fn _spool_replay(&self, spool: &SlackSpool) {
+        let files = match spool.list() {
+            Err(e) => {
+                error!(self.logger, "slack_spool_exception: {}", e);
+
+                return
+            },
+            Ok(files) => {
+                files
+            }
+        };
+
+        for path in files.iter() {
+            let age = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0);
+
+            let message: Option<SlackMessage> = fs::read_to_string(path).ok().and_then(|data| serde_json::from_str(&data).ok());
+
+            let message = match message {
+                Some(message) if age <= SLACK_SPOOL_MAX_AGE_SECS => {
+                    message
+                },
+                _ => {
+                    error!(self.logger, "slack_spool_dead_letter: invalid or expired message"; "path" => path, "age" => age);
+
+                    self._spool_dead_letter(spool, path);
+
+                    continue
+                }
+            };
+
+            match SlackDeliver::call(&message, &self.config, &self.state, &self.logger) {
+                Ok(_) => {
+                    info!(self.logger, "slack_spool_replay_ok"; "path" => path, "id" => &message.id);
+
+                    let _ = fs::remove_file(path);
+                },
+                Err(SlackChatError::Permanent(e)) => {
+                    error!(self.logger, "slack_spool_dead_letter: {}", e; "path" => path, "id" => &message.id);
+
+                    self._spool_dead_letter(spool, path);
+                },
+                Err(e) => {
+                    error!(self.logger, "slack_spool_replay_exception: {}", e; "path" => path, "id" => &message.id);
+
+                    return
+                }
+            };
+        }
+    }
+
+    fn _spool_dead_letter(&self, spool: &SlackSpool, path: &str) {
+        match spool.dead_letter(path) {
+            Ok(_) => {},
+            Err(e) => {
+                error!(self.logger, "slack_spool_exception: {}", e; "path" => path);
+
+                let _ = fs::remove_file(path);
+            }
+        };
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use reqwest::StatusCode;
+
+    fn temp_spool() -> SlackSpool {
+        SlackSpool::new(&std::env::temp_dir().join(format!("deploybot-test-slack-{}", Ulid::new())).to_string_lossy())
+    }
+
+    #[test]
+    fn result_ok() {
+        assert!(SlackChatPost::result(StatusCode::OK, None, r#"{"ok":true}"#).is_ok());
+    }
+
+    #[test]
+    fn result_rate_limited() {
+        match SlackChatPost::result(StatusCode::TOO_MANY_REQUESTS, Some(" 30 "), "") {
+            Err(SlackChatError::RateLimited(30)) => {},
+            result => panic!("{:?}", result),
+        };
+
+        // without a valid retry-after header
+        match SlackChatPost::result(StatusCode::TOO_MANY_REQUESTS, Some("soon"), "") {
+            Err(SlackChatError::RateLimited(SLACK_DELIVER_BACKOFF_SECS)) => {},
+            result => panic!("{:?}", result),
+        };
+    }
+
+    #[test]
+    fn result_transient() {
+        for (status, body) in [
+            (StatusCode::BAD_GATEWAY, ""),
+            (StatusCode::OK, r#"{"ok":false,"error":"service_unavailable"}"#),
+            (StatusCode::OK, "<html>"),
+        ].iter() {
+            match SlackChatPost::result(*status, None, body) {
+                Err(SlackChatError::Transient(_)) => {},
+                result => panic!("{} {}: {:?}", status, body, result),
+            };
+        }
+    }
+
+    #[test]
+    fn result_permanent() {
+        for (status, body) in [
+            (StatusCode::FORBIDDEN, ""),
+            (StatusCode::OK, r#"{"ok":false,"error":"channel_not_found"}"#),
+            (StatusCode::OK, r#"{"ok":false}"#),
+        ].iter() {
+            match SlackChatPost::result(*status, None, body) {
+                Err(SlackChatError::Permanent(_)) => {},
+                result => panic!("{} {}: {:?}", status, body, result),
+            };
+        }
+    }
+
+    #[test]
+    fn deliver_sleep_stops_on_spool() {
+        let state = AtomicU8::new(SLACK_THREAD_SPOOL);
+        let started = time::Instant::now();
+
+        match SlackDeliver::sleep(60, &state) {
+            Err(SlackChatError::Transient(_)) => {},
+            result => panic!("{:?}", result),
+        };
+
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+
+        assert!(SlackDeliver::sleep(0, &AtomicU8::new(SLACK_THREAD_RUN)).is_ok());
+    }
+
+    #[test]
+    fn spool_write_list_dead_letter() {
+        let spool = temp_spool();
+
+        let first = spool.write("{\"id\":\"1\"}").unwrap();
+        let second = spool.write("{\"id\":\"2\"}").unwrap();
+
+        // ulids created in the same millisecond aren't ordered
+        let mut written = vec![first.to_string(), second.to_string()];
+        written.sort();
+
+        assert_eq!(spool.list().unwrap(), written);
+
+        let dead = spool.dead_letter(&first).unwrap();
+
+        assert_eq!(spool.list().unwrap(), vec![second]);
+        assert_eq!(fs::read_to_string(&dead).unwrap(), "{\"id\":\"1\"}");
+
+        let dead = spool.dead_write("{\"id\":\"3\"}").unwrap();
+
+        assert!(dead.starts_with(&format!("{}/dead/", spool.dir)));
+        assert_eq!(spool.list().unwrap().len(), 1);
+
+        fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+    #[test]
+    fn spool_list_missing_dir() {
+        assert!(temp_spool().list().unwrap().is_empty());
+    }
 }
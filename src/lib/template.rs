@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+//
+// minimal template rendering for kubernetes manifests, e.g.
+//
+// image: {{ image }}
+// env:
+//   - name: GIT_SHA
+//     value: "{{ git_sha }}"
+//   - name: LOG_LEVEL
+//     value: "{{ vars.log_level }}"
+//
+
+pub type TemplateVars = BTreeMap<String, String>;
+
+// variables set for every deploy, resource 'vars' are added as 'vars.<key>'
+pub const TEMPLATE_BUILTINS: [&str; 9] = [
+    "deploy_id",
+    "git_repo",
+    "git_sha",
+    "git_tag",
+    "image",
+    "image_digest",
+    "image_name",
+    "image_tag",
+    "resource_name",
+];
+
+// variables set before the images are built, for build args and labels
+pub const TEMPLATE_BUILD_BUILTINS: [&str; 5] = [
+    "deploy_id",
+    "git_repo",
+    "git_sha",
+    "git_tag",
+    "resource_name",
+];
+
+#[derive(Debug)]
+pub struct TemplateError {
+    pub name: String,
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct TemplateRender {}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {} '{}'", self.line, self.message, self.name)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl TemplateRender {
+    //
+    // replace '{{ name }}' with its value, undefined variables are an error in strict mode
+    // and are left as is otherwise
+    //
+
+    pub fn call(input: &str, vars: &TemplateVars, strict: bool) -> Result<String, TemplateError> {
+        let mut output = String::with_capacity(input.len());
+        let mut rest = input;
+        let mut line = 1;  // line of the start of rest, counted as the input is consumed
+
+        while let Some(start) = rest.find("{{") {
+            output.push_str(&rest[..start]);
+
+            line += rest[..start].matches('\n').count();
+
+            let end = match rest[start..].find("}}") {
+                None => {
+                    if strict {
+                        return Err(TemplateError {
+                            name: rest[start..].lines().next().unwrap_or("").to_string(),
+                            line: line,
+                            message: "unterminated variable".to_string(),
+                        })
+                    }
+
+                    rest = &rest[start..];
+
+                    break
+                },
+                Some(end) => {
+                    start + end
+                }
+            };
+
+            let name = rest[start + 2..end].trim();
+
+            match vars.get(name) {
+                Some(value) => {
+                    output.push_str(value);
+                },
+                None => {
+                    if strict {
+                        return Err(TemplateError {
+                            name: name.to_string(),
+                            line: line,
+                            message: "undefined variable".to_string(),
+                        })
+                    }
+
+                    output.push_str(&rest[start..end + 2]);
+                }
+            };
+
+            line += rest[start..end + 2].matches('\n').count();
+            rest = &rest[end + 2..];
+        }
+
+        output.push_str(rest);
+
+        Ok(output)
+    }
+
+    // list variable names used in the input, with their line numbers
+    pub fn variables(input: &str) -> Vec<(String, usize)> {
+        let mut names = Vec::new();
+
+        for (i, line) in input.lines().enumerate() {
+            let mut rest = line;
+
+            while let Some(start) = rest.find("{{") {
+                let end = match rest[start..].find("}}") {
+                    None => {
+                        break
+                    },
+                    Some(end) => {
+                        start + end
+                    }
+                };
+
+                names.push((rest[start + 2..end].trim().to_string(), i + 1));
+
+                rest = &rest[end + 2..];
+            }
+        }
+
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> TemplateVars {
+        let mut vars = TemplateVars::new();
+        vars.insert("git_sha".to_string(), "1a2b3c4".to_string());
+        vars.insert("vars.replicas".to_string(), "2".to_string());
+
+        vars
+    }
+
+    #[test]
+    fn render() {
+        let output = TemplateRender::call("sha: {{ git_sha }}\nreplicas: {{vars.replicas}}\n", &vars(), true).unwrap();
+
+        assert_eq!(output, "sha: 1a2b3c4\nreplicas: 2\n");
+    }
+
+    #[test]
+    fn render_without_variables() {
+        assert_eq!(TemplateRender::call("image: :image_name\n", &vars(), true).unwrap(), "image: :image_name\n");
+    }
+
+    #[test]
+    fn render_strict_undefined() {
+        let e = TemplateRender::call("a: 1\nb: {{ git_sha }}\nc: {{ git_shaa }}\n", &vars(), true).unwrap_err();
+
+        assert_eq!(e.name, "git_shaa");
+        assert_eq!(e.line, 3);
+        assert_eq!(e.message, "undefined variable");
+    }
+
+    #[test]
+    fn render_strict_unterminated() {
+        let e = TemplateRender::call("a: 1\nb: {{ git_sha\n", &vars(), true).unwrap_err();
+
+        assert_eq!(e.line, 2);
+        assert_eq!(e.message, "unterminated variable");
+    }
+
+    #[test]
+    fn render_lenient() {
+        let output = TemplateRender::call("a: {{ other }} {{ git_sha }}\nb: {{ open\n", &vars(), false).unwrap();
+
+        assert_eq!(output, "a: {{ other }} 1a2b3c4\nb: {{ open\n");
+    }
+
+    #[test]
+    fn variables() {
+        let names = TemplateRender::variables("a: {{ git_sha }}\n\nb: {{ vars.replicas }} {{image}}\nc: {{ open\n");
+
+        assert_eq!(names, vec![
+            ("git_sha".to_string(), 1),
+            ("vars.replicas".to_string(), 3),
+            ("image".to_string(), 3),
+        ]);
+    }
+}
@@ -1,4 +1,4 @@
-use super::kube_resource::{KubeResourceParser, KubeResourceResolve};
+use super::kube_resource::KubeResource;
 
 use std::io::{Error, ErrorKind, Result};
 use std::process::{Command};
@@ -14,8 +14,7 @@ pub struct WatchObject {
 #[derive(Debug)]
 pub struct WatchStage {
     pub id: String,
-    pub resource_file: String,
-    pub resource_key: String,
+    pub resource: KubeResource,
     pub logger: slog::Logger,
 }
 
@@ -45,85 +44,30 @@ impl WatchObject {
 
 impl WatchStage {
 
-    pub fn new(id: &String, resource_path: &String, logger: slog::Logger) -> WatchStage {
-        let resource_vec: Vec<_> = resource_path.split(":").collect();
-        let resource_file = KubeResourceResolve::call(id, resource_path);
-
+    pub fn new(id: &String, resource: &KubeResource, logger: slog::Logger) -> WatchStage {
         WatchStage {
             id: id.to_owned(),
-            resource_file: resource_file.to_owned(),
-            resource_key: resource_vec[1].to_owned(),
+            resource: resource.clone(),
             logger: logger,
         }
     }
 
     pub fn call(&self) -> Result<Vec<WatchObject>> {
-        let resource_parser = KubeResourceParser::new(
-            &self.resource_file,
-            &self.resource_key,
-        );
-
-        // println!("watch {}, {}", self.resource_file, self.resource_key);
-
-        let resource = match resource_parser.call() {
-            Some(value) => {
-                value
-            },
-            None => {
-                return Err(Error::new(ErrorKind::Other, "watches missing"))
-            }
-        };
+        if self.resource.watches.is_empty() {
+            return Err(Error::new(ErrorKind::Other, "watches missing"))
+        }
 
         // build list of watch objects
 
-        match resource.get("watches") {
-            Some(value) => {
-                let v: Vec<WatchObject> = value.as_array().unwrap().to_vec().iter()
-                    .map(|o| self._watch_object(o) )
-                    .filter_map(|o| o.ok())
-                    .collect();
-
-                return Ok(v)
-            },
-            None => {
-                return Err(Error::new(ErrorKind::Other, "watches missing"))
-            }
-        };
-    }
+        let v: Vec<WatchObject> = self.resource.watches.iter()
+            .map(|watch| WatchObject {
+                cmd: watch.cmd.to_string(),
+                sleep: watch.sleep,
+                wait: watch.wait,
+            })
+            .collect();
 
-    fn _watch_object(&self, watch: &toml::Value) -> Result<WatchObject> {
-        let cmd = match watch.get("cmd") {
-            None => {
-                return Err(Error::new(ErrorKind::Other, "watch cmd required"))
-            },
-            Some(value) => {
-                value.as_str().unwrap().to_string() // remove string quotes
-            }
-        };
-
-        let wait = match watch.get("wait") {
-            None => {
-                60
-            },
-            Some(value) => {
-                value.as_integer().unwrap()
-            }
-        };
-
-        let sleep = match watch.get("sleep") {
-            None => {
-                20
-            },
-            Some(value) => {
-                value.as_integer().unwrap()
-            }
-        };
-
-        Ok(WatchObject {
-            cmd: cmd,
-            sleep: sleep as u64,
-            wait: wait as u64,
-        })
+        Ok(v)
     }
 
 }
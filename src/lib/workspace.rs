@@ -0,0 +1,300 @@
+use super::config::{Config, WorkspaceConfig};
+use super::deploy_record::DeployRecordRead;
+use super::fs::{FsDockerConfig, FsRecord, FsRoot, FsTmp};
+
+use slog::{error, info, warn};
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::process::Command;
+
+// deploy ids are ulids, other entries in the workspace dir (records, git mirrors) are never touched
+const WORKSPACE_ID_LEN: usize = 26;
+
+//
+// workspace lifecycle, a completed deploy's checkout is removed or archived, failed checkouts are
+// kept for debugging up to keep_failed, ids are ulids so sorting them sorts by deploy time
+//
+
+#[derive(Debug)]
+pub struct WorkspaceRetain {
+    pub id: String,
+    pub config: WorkspaceConfig,
+    pub logger: slog::Logger,
+}
+
+#[derive(Debug)]
+pub struct WorkspaceCollect {
+    pub config: WorkspaceConfig,
+    pub logger: slog::Logger,
+}
+
+#[derive(Debug)]
+pub struct WorkspaceDiskCheck {
+    pub dirs: Vec<String>,
+    pub min_free_mb: u64,
+}
+
+impl WorkspaceRetain {
+    pub fn new(id: &String, config: &WorkspaceConfig, logger: slog::Logger) -> WorkspaceRetain {
+        WorkspaceRetain {
+            id: id.to_owned(),
+            config: config.clone(),
+            logger: logger,
+        }
+    }
+
+    pub fn call(&self, code: i32) -> Option<i32> {
+        if code == 0 {
+            let result = if self.config.archive_dir.is_empty() {
+                fs::remove_dir_all(FsRoot::call(&self.id))
+            } else {
+                self._archive()
+            };
+
+            match result {
+                Ok(_) => {
+                    info!(self.logger, "workspace_retain_ok"; "archived" => !self.config.archive_dir.is_empty(), "id" => &self.id);
+                },
+                Err(e) => {
+                    error!(self.logger, "workspace_retain_exception: {}", e; "id" => &self.id);
+                }
+            };
+        }
+
+        // the deploy thread runs one deploy at a time, every other checkout is a failed deploy
+        WorkspacePrune::call(&FsRoot::dir(), "", self.config.keep_failed, &self.logger);
+
+        if !self.config.archive_dir.is_empty() {
+            WorkspacePrune::call(&self.config.archive_dir, ".tar.gz", self.config.keep_archived, &self.logger);
+        }
+
+        self._records_prune();
+
+        Some(0)
+    }
+
+    // oldest records past keep_records, except those the latest index points at, changelog bases
+    fn _records_prune(&self) {
+        let latest: HashSet<String> = match fs::read_dir(format!("{}/latest", FsRecord::dir())) {
+            Err(_) => {
+                HashSet::new()
+            },
+            Ok(entries) => {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+                    .map(|id| id.trim().to_string())
+                    .collect()
+            }
+        };
+
+        let list: Vec<(String, String)> = WorkspaceList::call(&FsRecord::dir(), ".json").into_iter()
+            .filter(|(id, _)| !latest.contains(id))
+            .collect();
+
+        if list.len() <= self.config.keep_records {
+            return
+        }
+
+        for (id, path) in list[..list.len() - self.config.keep_records].iter() {
+            match fs::remove_file(path) {
+                Ok(_) => {
+                    info!(self.logger, "workspace_prune_record"; "id" => id);
+                },
+                Err(e) => {
+                    warn!(self.logger, "workspace_prune_exception: {}", e; "path" => path, "id" => id);
+                }
+            };
+        }
+    }
+
+    // tar the checkout without its git objects, the deploy record has the sha
+    fn _archive(&self) -> Result<()> {
+        fs::create_dir_all(&self.config.archive_dir)?;
+
+        let status = Command::new("tar")
+            .args(&["-czf", &format!("{}/{}.tar.gz", self.config.archive_dir, self.id)])
+            .args(&["--exclude=.git", "-C", &FsRoot::dir(), &self.id])
+            .status()?;
+
+        if !status.success() {
+            return Err(Error::new(ErrorKind::Other, format!("tar {}", status)))
+        }
+
+        fs::remove_dir_all(FsRoot::call(&self.id))
+    }
+}
+
+impl WorkspaceCollect {
+    pub fn new(config: &WorkspaceConfig, logger: slog::Logger) -> WorkspaceCollect {
+        WorkspaceCollect {
+            config: config.clone(),
+            logger: logger,
+        }
+    }
+
+    //
+    // on startup no deploy is running, remove checkouts of deploys that were interrupted or
+    // completed without cleanup, markers, and registry credentials left by a crash
+    //
+
+    pub fn call(&self) -> Option<i32> {
+        for (id, path) in WorkspaceList::call(&FsRoot::dir(), "").iter() {
+            let failed = DeployRecordRead::call(id).map(|record| record.state == "failed").unwrap_or(false);
+
+            if !failed {
+                match fs::remove_dir_all(path) {
+                    Ok(_) => {
+                        info!(self.logger, "workspace_collect_orphan"; "id" => id);
+                    },
+                    Err(e) => {
+                        error!(self.logger, "workspace_collect_exception: {}", e; "id" => id);
+                    }
+                };
+            }
+        }
+
+        for (id, path) in WorkspaceList::call(&FsRoot::dir(), ".txt").iter() {
+            let _ = fs::remove_file(path);
+
+            info!(self.logger, "workspace_collect_marker"; "id" => id);
+        }
+
+        for (id, path) in WorkspaceList::call(&FsDockerConfig::dir(), "").iter() {
+            let _ = fs::remove_dir_all(path);
+
+            info!(self.logger, "workspace_collect_docker_config"; "id" => id);
+        }
+
+        WorkspacePrune::call(&FsRoot::dir(), "", self.config.keep_failed, &self.logger);
+
+        Some(0)
+    }
+}
+
+impl WorkspaceDiskCheck {
+    // every dir a deploy writes to, the checkouts, git mirrors and the deploybot tmp dir
+    pub fn new(config: &Config) -> WorkspaceDiskCheck {
+        let mut dirs = vec![config.workspace.dir.to_string(), FsTmp::dir()];
+
+        if config.git.mirror {
+            dirs.push(config.git.mirror_dir.to_string());
+        }
+
+        WorkspaceDiskCheck {
+            dirs: dirs,
+            min_free_mb: config.workspace.min_free_mb,
+        }
+    }
+
+    // least free mb of the dirs, an error if it's below min_free_mb, 0 disables the check
+    pub fn call(&self) -> Result<u64> {
+        if self.min_free_mb == 0 {
+            return Ok(0)
+        }
+
+        let mut free_min = u64::MAX;
+
+        for dir in self.dirs.iter() {
+            fs::create_dir_all(dir)?;
+
+            let free_mb = WorkspaceDiskCheck::_free_mb(dir)?;
+
+            if free_mb < self.min_free_mb {
+                return Err(Error::new(ErrorKind::Other, format!("{}mb free in {}, {}mb required", free_mb, dir, self.min_free_mb)))
+            }
+
+            free_min = free_min.min(free_mb);
+        }
+
+        Ok(free_min)
+    }
+
+    // statvfs, available to unprivileged users, it doesn't block like running df would
+    #[allow(clippy::unnecessary_cast)]  // fsblkcnt_t and c_ulong are 32 bit on some targets
+    fn _free_mb(dir: &str) -> Result<u64> {
+        let path = CString::new(dir)?;
+
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(Error::last_os_error())
+        }
+
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64 / 1024 / 1024)
+    }
+}
+
+#[derive(Debug)]
+struct WorkspaceList {}
+
+#[derive(Debug)]
+struct WorkspacePrune {}
+
+impl WorkspaceList {
+    // deploy id entries in dir with the suffix, oldest first
+    fn call(dir: &str, suffix: &str) -> Vec<(String, String)> {
+        let entries = match fs::read_dir(dir) {
+            Err(_) => {
+                return Vec::new()
+            },
+            Ok(entries) => {
+                entries
+            }
+        };
+
+        let mut list: Vec<(String, String)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let id = name.strip_suffix(suffix)?.to_string();
+
+                // a dir for no suffix, a file otherwise
+                if suffix.is_empty() != entry.path().is_dir() {
+                    return None
+                }
+
+                if id.len() != WORKSPACE_ID_LEN || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+                    return None
+                }
+
+                Some((id, entry.path().to_string_lossy().to_string()))
+            })
+            .collect();
+
+        list.sort();
+
+        list
+    }
+}
+
+impl WorkspacePrune {
+    // remove the oldest entries past keep
+    fn call(dir: &str, suffix: &str, keep: usize, logger: &slog::Logger) {
+        let list = WorkspaceList::call(dir, suffix);
+
+        if list.len() <= keep {
+            return
+        }
+
+        for (id, path) in list[..list.len() - keep].iter() {
+            let result = if Path::new(path).is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            };
+
+            match result {
+                Ok(_) => {
+                    info!(logger, "workspace_prune_ok"; "path" => path, "id" => id);
+                },
+                Err(e) => {
+                    warn!(logger, "workspace_prune_exception: {}", e; "path" => path, "id" => id);
+                }
+            };
+        }
+    }
+}
@@ -8,14 +8,20 @@ use crossbeam_channel::{bounded, unbounded};
 use dotenv;
 use slog::*;
 use std::process;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::api::deploys::deploys_create;
+use crate::api::deploys::{deploys_create, deploys_get};
 use crate::api::ping::ping;
 use crate::handlers::register;
+use crate::lib::config::ConfigLoad;
 use crate::lib::deploy::DeployThread;
-use crate::lib::slack::SlackThread;
+use crate::lib::fs::FsRoot;
+use crate::lib::kube_validate::KubeValidateCommand;
+use crate::lib::shutdown::{ShutdownDrain, ShutdownHandles, ShutdownSignals};
+use crate::lib::slack::{SlackThread, SLACK_THREAD_RUN};
+use crate::lib::workspace::WorkspaceCollect;
 
 mod api;
 mod handlers;
@@ -24,14 +30,21 @@ mod schemas;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+
+    // validate subcommand runs without server config, e.g. in app repo ci
+    if args.get(1).map(|s| s.as_str()) == Some("validate") {
+        process::exit(KubeValidateCommand::call(&args[2..]));
+    }
+
     dotenv::dotenv().ok();
     std::env::set_var("RUST_LOG", "actix_web=info,info");
     env_logger::init();
 
-    // register sigint handler
-    let _signal = unsafe { signal_hook::low_level::register(signal_hook::consts::SIGINT, || process::abort()) }?;
+    // sigterm and sigint drain deploys, a second signal exits
+    let shutdown = Arc::new(AtomicBool::new(false));
 
-    let listen_address = dotenv::var("LISTEN_ADDRESS").unwrap();  // e.g. 0.0.0.0:80
+    ShutdownSignals::call(&shutdown)?;
 
     // create logger
     let logger = Logger::root(
@@ -39,55 +52,117 @@ async fn main() -> std::io::Result<()> {
         o!(),
     );
 
+    // load and validate config before starting any threads
+    let config = match ConfigLoad::call() {
+        Err(e) => {
+            error!(logger, "config_exception: {}", e);
+
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))
+        },
+        Ok(config) => {
+            config
+        }
+    };
+
+    let listen_address = config.listen_address.clone();  // e.g. 0.0.0.0:80
+
+    // workspaces left by a previous run, before the deploy thread starts
+    FsRoot::init(&config.workspace.dir);
+    WorkspaceCollect::new(&config.workspace, logger.clone()).call();
+
     // create channels for sending and receiving messages
     let (deploy_sender, deploy_receiver) = bounded::<String>(1);
     let (slack_sender, slack_receiver) = unbounded::<String>();
 
     // create app data objects
+    let app_config = web::Data::new(config.clone());
     let app_logger = web::Data::new(logger.clone());
     let app_channel = web::Data::new(deploy_sender.clone());
+    let app_shutdown = web::Data::from(shutdown.clone());
 
     // create deploy thread
-    thread::spawn({
+    let deploy_thread = thread::spawn({
         let deploy_channel = deploy_receiver.clone();
         let slack_channel = slack_sender.clone();
+        let config = config.clone();
+        let shutdown = shutdown.clone();
         let logger = logger.clone();
 
         move || {
             DeployThread::new(
                 deploy_channel,
                 slack_channel,
+                config,
+                shutdown,
                 logger,
             ).call();
         }
     });
 
+    // stopped by the shutdown once deploys are drained
+    let slack_state = Arc::new(AtomicU8::new(SLACK_THREAD_RUN));
+
     // create slack thread
-    thread::spawn({
+    let slack_thread = thread::spawn({
         let slack_channel = slack_receiver.clone();
+        let slack_state = slack_state.clone();
+        let slack_config = config.slack.clone();
         let logger = logger.clone();
 
         move || {
             SlackThread::new(
                 slack_channel,
+                slack_state,
+                slack_config,
                 logger,
             ).call();
         }
     });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(app_config.clone())
             .app_data(app_logger.clone())
             .app_data(app_channel.clone())
+            .app_data(app_shutdown.clone())
             .app_data(web::JsonConfig::default().limit(4096))
             .configure(register)
             .wrap(middleware::Logger::default())
             // register handlers
             .service(web::resource("/api/v1/deploys").route(web::post().to(deploys_create)))
+            .service(web::resource("/api/v1/deploys/{id}").route(web::get().to(deploys_get)))
             .service(web::resource("/ping").route(web::get().to(ping)))
             .default_service(web::to(|| async { "404" }))
     })
+    .disable_signals()
     .bind(listen_address)?
-    .run()
-    .await
+    .run();
+
+    // drain on shutdown, the server stops before queued deploys are spooled
+    let shutdown_thread = thread::spawn({
+        let server_handle = server.handle();
+        let shutdown = shutdown.clone();
+        let config = config.clone();
+        let logger = logger.clone();
+
+        move || {
+            let handles = ShutdownHandles {
+                server: server_handle,
+                deploy_thread: deploy_thread,
+                deploy_channel: deploy_receiver,
+                slack_thread: slack_thread,
+                slack_channel: slack_receiver,
+                slack_state: slack_state,
+            };
+
+            ShutdownDrain::new(&config, logger).call(shutdown, handles);
+        }
+    });
+
+    server.await?;
+
+    // slack messages are flushed after the server stops
+    let _ = shutdown_thread.join();
+
+    Ok(())
 }